@@ -1,10 +1,12 @@
 use cem::{v2, V2, Scene};
 use cgmath::{Point3, Matrix4, Deg, InnerSpace};
+use std::collections::HashSet;
 use std::fmt::{self, Write};
-use std::str::FromStr;
+use lod::lod_name;
+use light::Light;
 
 // TODO: Date and Time modified
-pub const HEADER: &'static str = r#"<?xml version="1.0" encoding="utf-8"?>
+pub const HEADER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
 <COLLADA xmlns="http://www.collada.org/2005/11/COLLADASchema" version="1.4.1">
   <asset>
     <contributor>
@@ -17,12 +19,10 @@ pub const HEADER: &'static str = r#"<?xml version="1.0" encoding="utf-8"?>
     <up_axis>Y_UP</up_axis>
   </asset>
   <library_cameras/>
-  <library_images/>
-  <library_geometries>
 "#;
 
-const FORMAT_POS: &'static str = r##"<param name="X" type="float"/><param name="Y" type="float"/><param name="Z" type="float"/>"##;
-const FORMAT_TEX: &'static str = r##"<param name="S" type="float"/><param name="T" type="float"/>"##;
+const FORMAT_POS: &str = r##"<param name="X" type="float"/><param name="Y" type="float"/><param name="Z" type="float"/>"##;
+const FORMAT_TEX: &str = r##"<param name="S" type="float"/><param name="T" type="float"/>"##;
 
 struct Geometry<'n> {
 	// Name
@@ -33,8 +33,8 @@ struct Geometry<'n> {
 	mesh_normals: Vec<f32>,
 	// Texture (S, T)
 	mesh_map: Vec<f32>,
-	// Indices (V1, V2, V3)
-	polygons: Vec<u32>
+	// Indices (V1, V2, V3) of each material, along with the symbol the material is bound to
+	polygons: Vec<(String, Vec<u32>)>
 }
 
 impl<'n> fmt::Display for Geometry<'n> {
@@ -66,17 +66,19 @@ impl<'n> fmt::Display for Geometry<'n> {
 
 		writeln!(f, r##"        <vertices id="{0}-mesh-vertices"><input semantic="POSITION" source="#{0}-mesh-positions"/></vertices>"##, self.name)?;
 
-		writeln!(f, r#"        <triangles count="{}">"#, self.polygons.len() / 3)?;
-		writeln!(f, r##"          <input semantic="VERTEX" source="#{}-mesh-vertices" offset="0"/>"##, self.name)?;
-		writeln!(f, r##"          <input semantic="NORMAL" source="#{}-mesh-normals" offset="1"/>"##, self.name)?;
-		writeln!(f, r##"          <input semantic="TEXCOORD" source="#{}-mesh-map" offset="2" set="0"/>"##, self.name)?;
+		for (material, polygons) in &self.polygons {
+			writeln!(f, r#"        <triangles material="{}" count="{}">"#, material, polygons.len() / 3)?;
+			writeln!(f, r##"          <input semantic="VERTEX" source="#{}-mesh-vertices" offset="0"/>"##, self.name)?;
+			writeln!(f, r##"          <input semantic="NORMAL" source="#{}-mesh-normals" offset="1"/>"##, self.name)?;
+			writeln!(f, r##"          <input semantic="TEXCOORD" source="#{}-mesh-map" offset="2" set="0"/>"##, self.name)?;
 
-		write!(f, r#"          <p>"#)?;
-		for index in &self.polygons {
-			write!(f, "{0} {0} {0} ", index)?;
+			write!(f, r#"          <p>"#)?;
+			for index in polygons {
+				write!(f, "{0} {0} {0} ", index)?;
+			}
+			writeln!(f, r#"          </p>"#)?;
+			writeln!(f, r#"        </triangles>"#)?;
 		}
-		writeln!(f, r#"          </p>"#)?;
-		writeln!(f, r#"        </triangles>"#)?;
 		writeln!(f, r#"      </mesh>"#)?;
 		write!(f, r#"    </geometry>"#)?;
 
@@ -85,209 +87,439 @@ impl<'n> fmt::Display for Geometry<'n> {
 	}
 }
 
-struct Light {
-	color: (f32, f32, f32),
-	unk: (u32, u32, u32)
+/// Id of a material of a model, which is also the symbol the geometry binds it to.
+fn material_id(name: &str, index: usize) -> String {
+	format!("{}-material{}", name, index)
 }
 
-impl FromStr for Light {
-	type Err = &'static str;
-
-	fn from_str(definition: &str) -> Result<Self, Self::Err> {
-		fn split(definition: &str) -> Option<(&str, &str, &str, &str, &str, &str)> {
-			let mut split = definition.split('_');
-
-			split.next()?; // "light"
-
-			let r = split.next()?;
-			let g = split.next()?;
-			let b = split.next()?;
-
-			let i = split.next()?;
-			let j = split.next()?;
-			let k = split.next()?;
-
-			Some((r, g, b, i, j, k))
-		};
-
-		let (r, g, b, i, j, k) = split(definition).ok_or("Invalid light definition")?;
-
-		let (r, g, b, i, j, k) = (
-			r.parse::<u32>().map_err(|_| "failed to parse number")?,
-			g.parse::<u32>().map_err(|_| "failed to parse number")?,
-			b.parse::<u32>().map_err(|_| "failed to parse number")?,
-			i.parse::<u32>().map_err(|_| "failed to parse number")?,
-			j.parse::<u32>().map_err(|_| "failed to parse number")?,
-			k.parse::<u32>().map_err(|_| "failed to parse number")?
-		);
-
-		Ok(Light {
-			color: (
-				(r as f32) / 255.0,
-				(g as f32) / 255.0,
-				(b as f32) / 255.0
-			),
-			unk: (i, j, k)
-		})
-	}
+/// Id of the image of a texture.
+fn image_id(texture_name: &str) -> String {
+	format!("{}-image", make_id(texture_name, "texture"))
 }
 
-
 fn write_meshes(name: &str, model: &V2, string: &mut String) {
-	let triangle_data = &model.lod_levels[0];
-	let mut polygons = vec![0; model.lod_levels[0].len() * 3];
+	for (lod, triangle_data) in model.lod_levels.iter().enumerate() {
+		let mut polygons = Vec::with_capacity(model.materials.len());
 
-	for &v2::Material { name: ref _name, texture: _texture, ref triangles, vertex_offset, vertex_count: _vertex_count, texture_name: ref _texture_name } in &model.materials {
-		let triangle_slice = triangles[0];
+		for (material_index, &v2::Material { ref triangles, vertex_offset, .. }) in model.materials.iter().enumerate() {
+			let triangle_slice = match triangles.get(lod) {
+				Some(&slice) if slice.len > 0 => slice,
+				_ => continue
+			};
 
-		for index in 0..triangle_slice.len {
-			let index = index + triangle_slice.offset;
-			let triangle = &triangle_data[index as usize];
+			let mut indices = Vec::with_capacity(triangle_slice.len as usize * 3);
 
-			let indices = (
-				vertex_offset + triangle.0,
-				vertex_offset + triangle.1,
-				vertex_offset + triangle.2
-			);
+			for triangle in &triangle_data[triangle_slice.offset as usize..(triangle_slice.offset + triangle_slice.len) as usize] {
+				indices.push(vertex_offset + triangle.0);
+				indices.push(vertex_offset + triangle.1);
+				indices.push(vertex_offset + triangle.2);
+			}
 
-			polygons[(index as usize)*3 + 0] = indices.0;
-			polygons[(index as usize)*3 + 1] = indices.1;
-			polygons[(index as usize)*3 + 2] = indices.2;
+			polygons.push((material_id(name, material_index), indices));
 		}
-	}
 
-	for (frame_index, frame) in model.frames.iter().enumerate() {
-		let framed_name = format!("{}_frame{}", name, frame_index);
+		let lod_name = lod_name(name, lod);
 
-		let mut geometry = Geometry {
-			name: if frame_index > 0 { &framed_name } else { name },
-			mesh_positions: vec![0.0; frame.vertices.len() * 3],
-			mesh_normals: vec![0.0; frame.vertices.len() * 3],
-			mesh_map: vec![0.0; frame.vertices.len() * 2],
-			polygons: polygons.clone()
-		};
+		for (frame_index, frame) in model.frames.iter().enumerate() {
+			let framed_name = format!("{}_frame{}", lod_name, frame_index);
 
-		let transform = Matrix4::from_angle_x(Deg(-90.0));
+			let mut geometry = Geometry {
+				name: if frame_index > 0 { &framed_name } else { &lod_name },
+				mesh_positions: vec![0.0; frame.vertices.len() * 3],
+				mesh_normals: vec![0.0; frame.vertices.len() * 3],
+				mesh_map: vec![0.0; frame.vertices.len() * 2],
+				polygons: polygons.clone()
+			};
 
-		for (index, vertex) in frame.vertices.iter().enumerate() {
-			let normal = (transform * vertex.normal.normalize().extend(0.0)).truncate();
-			let position = Point3::from_homogeneous(transform * vertex.position.to_homogeneous());
+			let transform = Matrix4::from_angle_x(Deg(-90.0));
 
-			geometry.mesh_positions[index*3 + 0] = position.x;
-			geometry.mesh_positions[index*3 + 1] = position.y;
-			geometry.mesh_positions[index*3 + 2] = position.z;
+			for (index, vertex) in frame.vertices.iter().enumerate() {
+				let normal = (transform * vertex.normal.normalize().extend(0.0)).truncate();
+				let position = Point3::from_homogeneous(transform * vertex.position.to_homogeneous());
 
-			geometry.mesh_normals[index*3 + 0] = normal.x;
-			geometry.mesh_normals[index*3 + 1] = normal.y;
-			geometry.mesh_normals[index*3 + 2] = normal.z;
+				geometry.mesh_positions[index*3] = position.x;
+				geometry.mesh_positions[index*3 + 1] = position.y;
+				geometry.mesh_positions[index*3 + 2] = position.z;
 
-			geometry.mesh_map[index*2 + 0] = vertex.texture.x;
-			geometry.mesh_map[index*2 + 1] = 1.0 - vertex.texture.y;
+				geometry.mesh_normals[index*3] = normal.x;
+				geometry.mesh_normals[index*3 + 1] = normal.y;
+				geometry.mesh_normals[index*3 + 2] = normal.z;
+
+				geometry.mesh_map[index*2] = vertex.texture.x;
+				geometry.mesh_map[index*2 + 1] = 1.0 - vertex.texture.y;
+			}
+
+			writeln!(string, "{}", geometry).unwrap();
 		}
+	}
+}
+
+fn write_morph(name: &str, model: &V2, string: &mut String) {
+	writeln!(string, "    <controller id=\"{0}-morph\" name=\"{0}-morph\">", name).unwrap();
+	writeln!(string, "      <morph source=\"#{}-mesh\" method=\"NORMALIZED\">", name).unwrap();
+
+	// Targets Array
+	writeln!(string, "        <source id=\"{}-targets\">", name).unwrap();
+	writeln!(string, "          <IDREF_array id=\"{}-targets-array\" count=\"{}\">", name, model.frames.len()-1).unwrap();
+
+	for frame_index in 1..model.frames.len() {
+		writeln!(string, "            {}_frame{}-mesh", name, frame_index).unwrap();
+	}
+
+	string.push_str("          </IDREF_array>\n");
+	writeln!(string, r##"<technique_common><accessor source="#{}-targets-array" count="{}" stride="1"><param name="IDREF" type="IDREF"/></accessor></technique_common>"##, name, model.frames.len()-1).unwrap();
+
+	string.push_str("        </source>\n");
 
-		writeln!(string, "{}", geometry).unwrap();
+	// Weights Array
+	writeln!(string, "        <source id=\"{}-weights\">", name).unwrap();
+	write!(string, "          <float_array id=\"{}-weights-array\" count=\"{}\">", name, model.frames.len()-1).unwrap();
+
+	for _ in 1..model.frames.len() {
+		string.push_str("0 ");
 	}
+
+	string.push_str("</float_array>\n");
+	writeln!(string, r##"<technique_common><accessor source="#{}-weights-array" count="{}" stride="1"><param name="MORPH_WEIGHT" type="float"/></accessor></technique_common>"##, name, model.frames.len()-1).unwrap();
+
+	string.push_str("        </source>\n");
+
+	string.push_str("        <targets>\n");
+	writeln!(string, "          <input semantic=\"MORPH_TARGET\" source=\"#{}-targets\"/>", name).unwrap();
+	writeln!(string, "          <input semantic=\"MORPH_WEIGHT\" source=\"#{}-weights\"/>", name).unwrap();
+	string.push_str("        </targets>\n");
+	string.push_str("      </morph>\n");
+	string.push_str("    </controller>\n");
 }
 
-pub fn convert(cem: Scene<V2>) -> String {
-	let mut string = String::new();
+/// Writes one source of float values, with the given params for each element.
+fn write_float_source(id: &str, values: &[f32], params: &[&str], string: &mut String) {
+	writeln!(string, "        <source id=\"{}\">", id).unwrap();
+	write!(string, "          <float_array id=\"{}-array\" count=\"{}\">", id, values.len()).unwrap();
 
-	string.push_str(HEADER);
+	for value in values {
+		write!(string, "{} ", value).unwrap();
+	}
 
-	write_meshes("Scene_Root", &cem.model, &mut string);
+	string.push_str("</float_array>\n");
+	write!(string, r##"<technique_common><accessor source="#{}-array" count="{}" stride="{}">"##, id, values.len() / params.len(), params.len()).unwrap();
 
-	string.push_str("  </library_geometries>\n");
+	for param in params {
+		write!(string, r#"<param name="{}" type="float"/>"#, param).unwrap();
+	}
 
-	string.push_str("  <library_lights>\n");
+	string.push_str("</accessor></technique_common>\n");
+	string.push_str("        </source>\n");
+}
 
-	for name in &cem.model.tag_points {
+/// Writes an animation with a linearly interpolated key for each frame, at `frame_rate` frames per second.
+fn write_animation(id: &str, target: &str, values: &[f32], params: &[&str], frame_rate: f32, string: &mut String) {
+	let frames = values.len() / params.len();
+	let times = (0..frames).map(|frame| frame as f32 / frame_rate).collect::<Vec<_>>();
 
-		writeln!(string, "    <light id=\"{}-light\"><technique_common>\n", name).unwrap();
+	writeln!(string, "    <animation id=\"{}\">", id).unwrap();
 
-		if name.starts_with("light_") {
-			match name.parse::<Light>() {
-				Ok(light) => {
+	write_float_source(&format!("{}-input", id), &times, &["TIME"], string);
+	write_float_source(&format!("{}-output", id), values, params, string);
 
-					writeln!(string, "    <point><color>{} {} {}</color><linear_attenuation>0.3</linear_attenuation></point>\n", light.color.0, light.color.1, light.color.2).unwrap();
-					string.push_str("    </technique_common></light>\n");
+	writeln!(string, "        <source id=\"{}-interpolation\">", id).unwrap();
+	write!(string, "          <Name_array id=\"{}-interpolation-array\" count=\"{}\">", id, frames).unwrap();
 
-					continue;
-				}
-				Err(message) => eprintln!("Failed to parse light \"{}\": {}", name, message)
-			}
+	for _ in 0..frames {
+		string.push_str("LINEAR ");
+	}
+
+	string.push_str("</Name_array>\n");
+	writeln!(string, r##"<technique_common><accessor source="#{}-interpolation-array" count="{}" stride="1"><param name="INTERPOLATION" type="name"/></accessor></technique_common>"##, id, frames).unwrap();
+	string.push_str("        </source>\n");
+
+	writeln!(string, "        <sampler id=\"{}-sampler\">", id).unwrap();
+	writeln!(string, "          <input semantic=\"INPUT\" source=\"#{}-input\"/>", id).unwrap();
+	writeln!(string, "          <input semantic=\"OUTPUT\" source=\"#{}-output\"/>", id).unwrap();
+	writeln!(string, "          <input semantic=\"INTERPOLATION\" source=\"#{}-interpolation\"/>", id).unwrap();
+	string.push_str("        </sampler>\n");
+
+	writeln!(string, "        <channel source=\"#{}-sampler\" target=\"{}\"/>", id, target).unwrap();
+	string.push_str("    </animation>\n");
+}
+
+/// Keys the weights of a morph controller so that each frame is fully shown at its own time step, and the frames
+/// in between are blended.
+fn write_morph_animation(name: &str, model: &V2, frame_rate: f32, string: &mut String) {
+	for target in 0..model.frames.len() - 1 {
+		let weights = (0..model.frames.len()).map(|frame| if frame == target + 1 { 1.0 } else { 0.0 }).collect::<Vec<f32>>();
+
+		write_animation(&format!("{}-weights-{}", name, target), &format!("{}-weights({})", name, target), &weights, &["MORPH_WEIGHT"], frame_rate, string);
+	}
+}
+
+/// Id of the node of a tag point.
+fn tag_id(node: &Node, tag_name: &str) -> String {
+	format!("{}-{}", node.id, make_id(tag_name, "tag"))
+}
+
+/// Keys the position of every tag point that moves between frames.
+fn write_tag_animations(node: &Node, frame_rate: f32, string: &mut String) {
+	let transform = Matrix4::from_angle_x(Deg(-90.0));
+
+	for (index, tag_name) in node.model.tag_points.iter().enumerate() {
+		let positions = node.model.frames.iter()
+			.filter_map(|frame| frame.tag_points.get(index))
+			.map(|position| Point3::from_homogeneous(transform * position.to_homogeneous()))
+			.collect::<Vec<_>>();
+
+		if positions.windows(2).all(|pair| pair[0] == pair[1]) {
+			continue;
 		}
 
-		string.push_str("    <point><color>1.0 1.0 1.0</color><linear_attenuation>0.3</linear_attenuation></point>\n");
-		string.push_str("    </technique_common></light>\n");
+		let values = positions.iter().flat_map(|position| vec![position.x, position.y, position.z]).collect::<Vec<f32>>();
+		let id = tag_id(node, tag_name);
+
+		write_animation(&format!("{}-location", id), &format!("{}/location", id), &values, &["X", "Y", "Z"], frame_rate, string);
 	}
+}
 
-	string.push_str("  </library_lights>\n");
+/// A model in the scene tree, along with the unique id that everything exported for it is named after.
+struct Node<'s> {
+	id: String,
+	name: &'s str,
+	model: &'s V2,
+	children: Vec<Node<'s>>
+}
 
-	string.push_str("  <library_controllers>\n");
+impl<'s> Node<'s> {
+	fn new(scene: &'s Scene<V2>, used: &mut HashSet<String>) -> Self {
+		let base = make_id(&scene.name, "Scene_Root");
 
-	let name = "Scene_Root"; // TODO
-	let model = &cem.model;
+		let mut id = base.clone();
+		let mut suffix = 1;
 
-	if cem.model.frames.len() > 1 {
-		writeln!(string, "    <controller id=\"{0}-morph\" name=\"{0}-morph\">", name).unwrap();
-		writeln!(string, "      <morph source=\"#{}-mesh\" method=\"NORMALIZED\">", name).unwrap();
+		while !used.insert(id.clone()) {
+			id = format!("{}_{}", base, suffix);
+			suffix += 1;
+		}
 
-		// Targets Array
-		writeln!(string, "        <source id=\"{}-targets\">", name).unwrap();
-		writeln!(string, "          <IDREF_array id=\"{}-targets-array\" count=\"{}\">", name, model.frames.len()-1).unwrap();
+		Node {
+			id,
+			name: &scene.name,
+			model: &scene.model,
+			children: scene.children.iter().map(|child| Node::new(child, used)).collect()
+		}
+	}
 
-		for frame_index in 1..model.frames.len() {
-			writeln!(string, "            {}_frame{}-mesh", name, frame_index).unwrap();
+	fn flatten<'n>(&'n self, nodes: &mut Vec<&'n Node<'s>>) {
+		nodes.push(self);
+
+		for child in &self.children {
+			child.flatten(nodes);
 		}
+	}
+}
 
-		string.push_str("          </IDREF_array>\n");
-		writeln!(string, r##"<technique_common><accessor source="#{}-targets-array" count="{}" stride="1"><param name="IDREF" type="IDREF"/></accessor></technique_common>"##, name, model.frames.len()-1).unwrap();
+/// Turns a name into something usable as an XML id, falling back to the default for empty names.
+fn make_id(name: &str, default: &str) -> String {
+	let id = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect::<String>();
 
-		string.push_str("        </source>\n");
+	if id.is_empty() { default.to_owned() } else { id }
+}
 
-		// Weights Array
-		writeln!(string, "        <source id=\"{}-weights\">", name).unwrap();
-		write!(string, "          <float_array id=\"{}-weights-array\" count=\"{}\">", name, model.frames.len()-1).unwrap();
+fn escape(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
 
-		for _ in 1..model.frames.len() {
-			string.push_str("0 ");
+/// Instance of the geometry of a level of detail, through its morph controller if the model is animated, with each
+/// material that has triangles in that level of detail bound to its symbol.
+fn instance(id: &str, node: &Node, lod: usize) -> String {
+	let mut bindings = String::new();
+
+	for (index, material) in node.model.materials.iter().enumerate() {
+		if material.triangles.get(lod).is_none_or(|selection| selection.len == 0) {
+			continue;
 		}
 
-		string.push_str("</float_array>\n");
-		writeln!(string, r##"<technique_common><accessor source="#{}-weights-array" count="{}" stride="1"><param name="MORPH_WEIGHT" type="float"/></accessor></technique_common>"##, name, model.frames.len()-1).unwrap();
+		write!(bindings, r##"<instance_material symbol="{0}" target="#{0}"><bind_vertex_input semantic="UVMap" input_semantic="TEXCOORD" input_set="0"/></instance_material>"##, material_id(&node.id, index)).unwrap();
+	}
+
+	let (element, url) = if node.model.frames.len() > 1 { ("instance_controller", "morph") } else { ("instance_geometry", "mesh") };
+
+	format!(r##"<{0} url="#{1}-{2}"><bind_material><technique_common>{3}</technique_common></bind_material></{0}>"##, element, id, url, bindings)
+}
+
+/// Writes an image for every texture in the scene, expected next to the COLLADA file as `<texture_name>.<texture_extension>`.
+fn write_images(nodes: &[&Node], texture_extension: &str, string: &mut String) {
+	let mut written = HashSet::new();
 
-		string.push_str("        </source>\n");
+	string.push_str("  <library_images>\n");
 
-		string.push_str("        <targets>\n");
-		writeln!(string, "          <input semantic=\"MORPH_TARGET\" source=\"#{}-targets\"/>", name).unwrap();
-		writeln!(string, "          <input semantic=\"MORPH_WEIGHT\" source=\"#{}-weights\"/>", name).unwrap();
-		string.push_str("        </targets>\n");
-		string.push_str("      </morph>\n");
-		string.push_str("    </controller>\n");
+	for material in nodes.iter().flat_map(|node| node.model.materials.iter()) {
+		if material.texture_name.is_empty() || !written.insert(&material.texture_name) {
+			continue;
+		}
+
+		writeln!(string, r#"    <image id="{}" name="{}"><init_from>{}.{}</init_from></image>"#, image_id(&material.texture_name), escape(&material.texture_name), escape(&material.texture_name), texture_extension).unwrap();
 	}
 
-	string.push_str("  </library_controllers>\n");
+	string.push_str("  </library_images>\n");
+}
 
-	string.push_str(r##"  <library_visual_scenes><visual_scene id="Scene" name="Scene">"##);
-	string.push('\n');
+/// Writes a material and a lambert effect for every material in the scene, using the texture as the diffuse color.
+fn write_materials(nodes: &[&Node], string: &mut String) {
+	string.push_str("  <library_effects>\n");
+
+	for node in nodes {
+		for (index, material) in node.model.materials.iter().enumerate() {
+			let id = material_id(&node.id, index);
 
-	writeln!(string, r##"<node id="{0}" name="{0}" type="NODE"><matrix sid="transform">1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1</matrix><instance_geometry url="#{0}-mesh"/>"##, name).unwrap();
+			writeln!(string, r#"    <effect id="{}-effect"><profile_COMMON>"#, id).unwrap();
 
-	{
+			if material.texture_name.is_empty() {
+				string.push_str(r#"      <technique sid="common"><lambert><diffuse><color sid="diffuse">0.8 0.8 0.8 1</color></diffuse></lambert></technique>"#);
+				string.push('\n');
+			} else {
+				let image = image_id(&material.texture_name);
+
+				writeln!(string, r#"      <newparam sid="{0}-surface"><surface type="2D"><init_from>{0}</init_from></surface></newparam>"#, image).unwrap();
+				writeln!(string, r#"      <newparam sid="{0}-sampler"><sampler2D><source>{0}-surface</source></sampler2D></newparam>"#, image).unwrap();
+				writeln!(string, r#"      <technique sid="common"><lambert><diffuse><texture texture="{}-sampler" texcoord="UVMap"/></diffuse></lambert></technique>"#, image).unwrap();
+			}
+
+			string.push_str("    </profile_COMMON></effect>\n");
+		}
+	}
+
+	string.push_str("  </library_effects>\n");
+
+	string.push_str("  <library_materials>\n");
+
+	for node in nodes {
+		for (index, material) in node.model.materials.iter().enumerate() {
+			let id = material_id(&node.id, index);
+			let name = if material.name.is_empty() { &id } else { &material.name };
+
+			writeln!(string, r##"    <material id="{0}" name="{1}"><instance_effect url="#{0}-effect"/></material>"##, id, escape(name)).unwrap();
+		}
+	}
+
+	string.push_str("  </library_materials>\n");
+}
+
+fn write_node(node: &Node, string: &mut String) {
+	// The root scene is usually unnamed, its node is named after the id so that it survives a round trip.
+	let name = if node.name.is_empty() { &node.id } else { node.name };
+
+	writeln!(string, r##"<node id="{0}" name="{1}" type="NODE"><matrix sid="transform">1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1</matrix>{2}"##, node.id, escape(name), instance(&node.id, node, 0)).unwrap();
+
+	// Tag points are placed where they are in the first frame, models without frames have nowhere to put them.
+	if let Some(frame) = node.model.frames.first() {
 		let transform = Matrix4::from_angle_x(Deg(-90.0));
 
-		for (tag_name, position) in cem.model.tag_points.iter().zip(cem.model.frames[0].tag_points.iter()) {
+		for (tag_name, position) in node.model.tag_points.iter().zip(frame.tag_points.iter()) {
 			let position = Point3::from_homogeneous(transform * position.to_homogeneous());
 
-			writeln!(string, "    <node name=\"{}\">\n", tag_name).unwrap();
-			writeln!(string, "    <translate>{} {} {}</translate>", position.x, position.y, position.z).unwrap();
-			writeln!(string, "    <instance_light url=\"#{}-light\" />\n", tag_name).unwrap();
+			writeln!(string, "    <node id=\"{}\" name=\"{}\">\n", tag_id(node, tag_name), escape(tag_name)).unwrap();
+			writeln!(string, "    <translate sid=\"location\">{} {} {}</translate>", position.x, position.y, position.z).unwrap();
+
+			// Only light tag points instance a light, the others stay empty nodes so that they are not imported as lights.
+			if tag_name.starts_with("light_") {
+				writeln!(string, "    <instance_light url=\"#{}-light\" />\n", tag_id(node, tag_name)).unwrap();
+			}
+
 			string.push_str("</node>");
 		}
 	}
 
+	for child in &node.children {
+		write_node(child, string);
+	}
+
 	string.push_str("</node>");
 
+	// Lower levels of detail sit next to the full detail mesh, see the lod module for the naming.
+	for lod in 1..node.model.lod_levels.len() {
+		let id = lod_name(&node.id, lod);
+
+		writeln!(string, r##"<node id="{0}" name="{1}" type="NODE"><matrix sid="transform">1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1</matrix>{2}</node>"##, id, escape(&lod_name(name, lod)), instance(&id, node, lod)).unwrap();
+	}
+}
+
+/// Converts a scene to COLLADA, with the frames of animated models played back at `frame_rate` frames per second.
+/// Textures are referenced as `<texture_name>.<texture_extension>`.
+pub fn convert(cem: Scene<V2>, frame_rate: f32, texture_extension: &str) -> String {
+	let mut string = String::new();
+
+	let root = Node::new(&cem, &mut HashSet::new());
+
+	let mut nodes = Vec::new();
+	root.flatten(&mut nodes);
+
+	string.push_str(HEADER);
+
+	write_images(&nodes, texture_extension, &mut string);
+	write_materials(&nodes, &mut string);
+
+	string.push_str("  <library_geometries>\n");
+
+	for node in &nodes {
+		write_meshes(&node.id, node.model, &mut string);
+	}
+
+	string.push_str("  </library_geometries>\n");
+
+	string.push_str("  <library_lights>\n");
+
+	for node in &nodes {
+		for name in node.model.tag_points.iter().filter(|name| name.starts_with("light_")) {
+
+			writeln!(string, "    <light id=\"{}-light\"><technique_common>\n", tag_id(node, name)).unwrap();
+
+			match name.parse::<Light>() {
+				Ok(light) => {
+					writeln!(string, "    <point><color>{} {} {}</color><linear_attenuation>0.3</linear_attenuation></point>\n", light.color.0, light.color.1, light.color.2).unwrap();
+				}
+				Err(message) => {
+					eprintln!("Failed to parse light \"{}\": {}", name, message);
+
+					string.push_str("    <point><color>1.0 1.0 1.0</color><linear_attenuation>0.3</linear_attenuation></point>\n");
+				}
+			}
+
+			string.push_str("    </technique_common></light>\n");
+		}
+	}
+
+	string.push_str("  </library_lights>\n");
+
+	string.push_str("  <library_controllers>\n");
+
+	for node in &nodes {
+		if node.model.frames.len() > 1 {
+			for lod in 0..node.model.lod_levels.len() {
+				write_morph(&lod_name(&node.id, lod), node.model, &mut string);
+			}
+		}
+	}
+
+	string.push_str("  </library_controllers>\n");
+
+	string.push_str("  <library_animations>\n");
+
+	for node in &nodes {
+		if node.model.frames.len() > 1 {
+			for lod in 0..node.model.lod_levels.len() {
+				write_morph_animation(&lod_name(&node.id, lod), node.model, frame_rate, &mut string);
+			}
+
+			write_tag_animations(node, frame_rate, &mut string);
+		}
+	}
+
+	string.push_str("  </library_animations>\n");
+
+	string.push_str(r##"  <library_visual_scenes><visual_scene id="Scene" name="Scene">"##);
+	string.push('\n');
+
+	write_node(&root, &mut string);
+
 	string.push_str(r##"  </visual_scene></library_visual_scenes>"##);
 	string.push('\n');
 
@@ -296,4 +528,4 @@ pub fn convert(cem: Scene<V2>) -> String {
 	string.push_str("</COLLADA>");
 
 	string
-}
\ No newline at end of file
+}
@@ -1,245 +1,968 @@
-use cem::{v2, V2, collider};
-use cgmath::{Point3, Point2, Vector3, Matrix4, Deg, InnerSpace};
-use collada::{Object, Shape, VTNIndex, TVertex, Vertex as NVertex};
+use cem::{v2, V2, Scene, collider};
+use cgmath::{Point3, Point2, Vector2, Vector3, Vector4, Matrix4, SquareMatrix, Deg, Rad, InnerSpace, EuclideanSpace};
 use collada::document::ColladaDocument;
 use std::collections::HashMap;
+use std::str::FromStr;
 use xml::{self, Element};
+use error::ConversionError;
+use lod::split_lod;
+use mesh_builder::{MaterialBuilder, MAX_FRAMES, layout, merge_vertices, normal_matrix, texture_name};
+use light::Light;
 
-pub fn convert(document: ColladaDocument) -> V2 {
-	let mut objects = HashMap::new();
+/// Converts the visual scene of a document. Animated models are sampled at `frame_rate` frames per second.
+pub fn convert(document: ColladaDocument, frame_rate: f32) -> Result<Scene<V2>, ConversionError> {
+	let ns = document.root_element.ns.as_ref().map(String::as_ref);
 
-	for object in document.get_obj_set().expect("No objects in collada document...").objects {
-		objects.insert(object.id.clone(), object);
-	}
+	let meshes = read_meshes(ns, &document.root_element)?;
 
-	let ns = document.root_element.ns.as_ref().map(String::as_ref);
+	if meshes.is_empty() {
+		return Err(ConversionError::collada("COLLADA/library_geometries", "no usable geometry in the document"));
+	}
 
 	// Find what frames are attached to each piece of geometry
-	let morph_links = document.root_element.get_child("library_controllers", ns).map(|controllers| {
-		controllers
-			.get_children("controller", ns)
-			.filter_map(|controller| controller.get_child("morph", ns))
-			.filter_map(|morph| {
-				if morph.get_attribute("method", None) == Some("RELATIVE") {
-					eprintln!("warning[collada]: unsupported morph method RELATIVE, treating it as NORMALIZED...");
-				}
+	let mut morph_links = HashMap::new();
+	// Geometry morphed by each controller, for <instance_controller>.
+	let mut controllers = HashMap::new();
+
+	for controller in document.root_element.get_children("library_controllers", ns).flat_map(|controllers| controllers.get_children("controller", ns)) {
+		let morph = match controller.get_child("morph", ns) {
+			Some(morph) => morph,
+			None => continue
+		};
 
-				let name = trim_hash(morph.get_attribute("source", None)?).to_owned();
+		let path = format!("COLLADA/library_controllers/controller[@id='{}']/morph", controller.get_attribute("id", None).unwrap_or(""));
 
-				// Ignore this for now since we don't do anything with preset morph weights.
-				//let weight = get_input(ns, morph, "MORPH_WEIGHT").and_then(|input_element| get_input_source(ns, morph, input_element))?;
+		// NORMALIZED targets are complete meshes, while RELATIVE targets only hold offsets from the base mesh.
+		let relative = match morph.get_attribute("method", None) {
+			Some("RELATIVE") => true,
+			Some("NORMALIZED") | None => false,
+			Some(method) => return Err(ConversionError::collada(path, format!("unknown morph method {}", method)))
+		};
+
+		let name = trim_hash(morph.get_attribute("source", None).ok_or_else(|| ConversionError::collada(path.clone(), "missing \"source\" attribute"))?).to_owned();
 
-				let target = get_input(ns, morph.get_child("targets", ns)?, "MORPH_TARGET")
-					.and_then(|input_element| get_input_source(ns, morph, input_element))?;
+		let targets = morph.get_child("targets", ns).ok_or_else(|| ConversionError::collada(path.clone(), "missing <targets>"))?;
 
+		let target_source = get_input(ns, targets, "MORPH_TARGET")
+			.and_then(|input_element| get_input_source(ns, morph, input_element))
+			.ok_or_else(|| ConversionError::collada(format!("{}/targets", path), "missing the MORPH_TARGET source"))?;
 
-				// TODO: We don't actually obey the standard here.
-				// We need to be using the accessor to read the array instead of reading the array directly.
-				// Alas, for now this works (at least with Blender).
+		let morph_targets = read_source::<String>(ns, target_source)?.into_iter().filter_map(|values| values.into_iter().next()).collect::<Vec<_>>();
 
-				let morph_targets = target.get_child("IDREF_array", ns)?
-					.children
-					.iter()
-					.filter_map(|child| if let &xml::Xml::CharacterNode(ref contents) = child { Some(contents) } else { None })
-					.find(|_| true)?
-					.trim()
-					.split_whitespace()
-					.map(str::to_owned)
-					.collect::<Vec<_>>();
+		let mut weights = vec![0.0; morph_targets.len()];
+		let mut weight_ids = Vec::new();
 
-				Some((name, morph_targets))
-			})
-			.collect::<HashMap<String, Vec<String>>>()
-	}).unwrap_or_else(HashMap::new);
+		if let Some(weight_source) = get_input(ns, targets, "MORPH_WEIGHT").and_then(|input_element| get_input_source(ns, morph, input_element)) {
+			weights = read_source::<f32>(ns, weight_source)?.into_iter().filter_map(|values| values.into_iter().next()).collect();
+
+			if weights.len() != morph_targets.len() {
+				return Err(ConversionError::collada(format!("{}/targets", path), format!("{} morph weights for {} morph targets", weights.len(), morph_targets.len())));
+			}
+
+			// Animations of the weights target either the source or the array inside it.
+			weight_ids.extend(weight_source.get_attribute("id", None).map(str::to_owned));
+			weight_ids.extend(weight_source.children.iter()
+				.filter_map(|child| if let xml::Xml::ElementNode(element) = child { element.get_attribute("id", None) } else { None })
+				.map(str::to_owned));
+		}
+
+		if let Some(id) = controller.get_attribute("id", None) {
+			controllers.insert(id.to_owned(), name.clone());
+		}
+
+		morph_links.insert(name, Morph { targets: morph_targets, relative, weights, weight_ids });
+	}
 
 	let primary_scene = trim_hash(document.root_element.get_child("scene", ns)
-		.expect("Collada document requires a root scene")
+		.ok_or_else(|| ConversionError::collada("COLLADA/scene", "document requires a root scene"))?
 		.get_child("instance_visual_scene", ns)
-		.expect("Collada document missing root visual scene")
+		.ok_or_else(|| ConversionError::collada("COLLADA/scene/instance_visual_scene", "document is missing the root visual scene"))?
 		.get_attribute("url", None)
-		.expect("<instance_visual_scene> missing \"url\" attribute"));
-
-
+		.ok_or_else(|| ConversionError::collada("COLLADA/scene/instance_visual_scene", "missing \"url\" attribute"))?);
 
 	let nodes = document.root_element.get_child("library_visual_scenes", ns)
-		.expect("Collada document has to have visual scenes")
+		.ok_or_else(|| ConversionError::collada("COLLADA/library_visual_scenes", "document has to have visual scenes"))?
 		.get_children("visual_scene", ns)
 		.find(|child| child.get_attribute("id", None) == Some(primary_scene))
-		.expect("The scene named in <instance_visual_scene> does not exist")
+		.ok_or_else(|| ConversionError::collada(format!("COLLADA/library_visual_scenes/visual_scene[@id='{}']", primary_scene), "the scene named in <instance_visual_scene> does not exist"))?
 		.get_children("node", ns);
 
-	let mut root_geometry = Vec::new();
+	// Nodes that <instance_node> can refer to, by id.
+	let mut library_nodes = HashMap::new();
+
+	for library in document.root_element.get_children("library_nodes", ns) {
+		for node in library.get_children("node", ns) {
+			index_nodes(ns, node, &mut library_nodes);
+		}
+	}
+
+	let animations = read_animations(ns, &document.root_element)?;
+	let materials = Materials::read(ns, &document.root_element);
+
+	let mut graph = SceneGraph {
+		ns,
+		library_nodes,
+		controllers,
+		lights: read_lights(ns, &document.root_element),
+		path: Vec::new(),
+		instancing: Vec::new(),
+		instances: Vec::new(),
+		tag_points: Vec::new()
+	};
 
 	for node in nodes {
+		graph.walk(node, Matrix4::identity(), None)?;
+	}
+
+	let SceneGraph { instances, tag_points, .. } = graph;
+
+	// Needed information extracted. Now begin conversion.
+
+	// Nodes named with a _lod<n> suffix hold the lower levels of detail of the node with the plain name.
+	// Each model also remembers the model it is nested under, which always comes before it.
+	let mut models: Vec<(String, Option<usize>, Vec<Option<Instance>>)> = Vec::new();
+
+	for instance in instances {
+		let (base, lod) = split_lod(&instance.node_name);
+
+		let parent = instance.parent.as_ref().and_then(|parent| models.iter().position(|(name, _, _)| name == parent));
+
+		let index = match models.iter().position(|(name, _, _)| name == base) {
+			Some(index) => index,
+			None => {
+				models.push((base.to_owned(), parent, Vec::new()));
+				models.len() - 1
+			}
+		};
+
+		let lods = &mut models[index].2;
+
+		while lods.len() <= lod {
+			lods.push(None);
+		}
+
+		if lods[lod].is_some() {
+			eprintln!("warning[collada]: node {} instances more than one geometry, ignoring {}", instance.node_name, instance.object_id);
+		} else {
+			lods[lod] = Some(instance);
+		}
+	}
+
+	if models.is_empty() {
+		return Err(ConversionError::collada(format!("COLLADA/library_visual_scenes/visual_scene[@id='{}']/node/instance_geometry", primary_scene), "no root geometry"));
+	}
+
+	// Tag points belong to the model they are nested under, or the root model if they are not nested under any.
+	let mut model_tag_points = vec![Vec::new(); models.len()];
+
+	for tag_point in &tag_points {
+		let owner = tag_point.parent.as_ref().and_then(|parent| models.iter().position(|(name, _, _)| name == parent)).unwrap_or(0);
+
+		model_tag_points[owner].push(tag_point);
+	}
+
+	let mut model_sources = Vec::with_capacity(models.len());
+
+	for (name, _, lods) in &models {
+		let lods = lods.iter().enumerate().filter_map(|(lod, instance)| {
+			if instance.is_none() {
+				eprintln!("warning[collada]: {} has no geometry for level of detail {}, skipping it", name, lod);
+			}
+
+			instance.as_ref()
+		}).collect::<Vec<&Instance>>();
+
+		let sources = read_sources(&meshes, &morph_links, &lods)?;
+
+		model_sources.push((lods, sources));
+	}
+
+	// Every model of the scene has the same frames. If anything is animated, every model is sampled over the whole
+	// animation. Otherwise the base geometry is the first frame and each morph target another, and models with fewer
+	// morph targets than others reuse their base geometry for the missing frames.
+	let animated = model_sources.iter().zip(model_tag_points.iter())
+		.any(|((lods, sources), tag_points)| animates(&animations, lods, sources, tag_points));
+
+	let frame_count = if animated {
+		animations.frame_count(frame_rate)?
+	} else {
+		model_sources.iter().flat_map(|(_, sources)| sources.iter()).map(|(_, _, targets)| targets.len() + 1).max().unwrap_or(1)
+	};
+
+	let sampling = Sampling { animations: &animations, frame_rate, animated, frame_count };
+
+	// The first node with geometry becomes the root model, and the others become submodels of the model they are nested
+	// under, or of the root model if they are not nested under any.
+	let mut scenes = Vec::with_capacity(models.len());
+
+	for (((name, _, _), (lods, sources)), tag_points) in models.iter().zip(model_sources.iter()).zip(model_tag_points.iter()) {
+		if !animated {
+			for (lod, (_, _, targets)) in sources.iter().enumerate() {
+				if targets.len() + 1 != frame_count {
+					eprintln!("warning[collada]: {} has {} frames at level of detail {} instead of {}, reusing its base geometry for the missing ones", name, targets.len() + 1, lod, frame_count);
+				}
+			}
+		}
+
+		scenes.push(Some(Scene {
+			name: name.clone(),
+			model: build_model(&materials, lods, sources, tag_points, &sampling)?,
+			children: Vec::new()
+		}));
+	}
+
+	// Children always come after their parents, so attaching them back to front builds the tree bottom up.
+	for index in (1..scenes.len()).rev() {
+		let scene = scenes[index].take().unwrap();
+		let parent = models[index].1.unwrap_or(0);
+
+		scenes[parent].as_mut().unwrap().children.insert(0, scene);
+	}
+
+	Ok(scenes[0].take().unwrap())
+}
+
+/// Geometry instanced somewhere in the visual scene.
+struct Instance<'a> {
+	node_name: String,
+	object_id: String,
+	/// Accumulated transform of the node and all of its ancestors.
+	transform: Matrix4<f32>,
+	/// Plain name of the closest ancestor that instances geometry.
+	parent: Option<String>,
+	/// Nodes from the root of the visual scene down to the instancing node, for evaluating animated transforms.
+	path: Vec<&'a Element>,
+	/// Id of the material bound to each material symbol of the geometry, from <bind_material>.
+	bindings: HashMap<String, String>
+}
+
+/// A light or an empty node, which becomes a tag point.
+struct TagPoint<'a> {
+	name: String,
+	/// Plain name of the closest ancestor that instances geometry.
+	parent: Option<String>,
+	/// Nodes from the root of the visual scene down to the tag point node.
+	path: Vec<&'a Element>
+}
+
+impl<'a> TagPoint<'a> {
+	/// Position of the tag point at the given time, following any animation of the nodes above it.
+	fn position(&self, animations: &Animations, time: f32) -> Result<Point3<f32>, ConversionError> {
+		let transform = Matrix4::from_angle_x(Deg(90.0)) * world_transform(&self.path, Some((animations, time)))?;
+
+		Ok(Point3::from_homogeneous(transform * Point3::new(0.0, 0.0, 0.0).to_homogeneous()))
+	}
+}
+
+/// Adds a node and every node nested in it to the id index used to resolve <instance_node>.
+fn index_nodes<'a>(ns: Option<&str>, node: &'a Element, index: &mut HashMap<&'a str, &'a Element>) {
+	if let Some(id) = node.get_attribute("id", None) {
+		index.insert(id, node);
+	}
+
+	for child in node.get_children("node", ns) {
+		index_nodes(ns, child, index);
+	}
+}
+
+/// Colors of the lights in the document, by id.
+fn read_lights<'a>(ns: Option<&str>, root: &'a Element) -> HashMap<&'a str, (f32, f32, f32)> {
+	let mut lights = HashMap::new();
+
+	for light in root.get_children("library_lights", ns).flat_map(|library| library.get_children("light", ns)) {
+		let color = light.get_child("technique_common", ns)
+			.and_then(|technique| ["point", "spot", "directional", "ambient"].iter().filter_map(|&kind| technique.get_child(kind, ns)).next())
+			.and_then(|kind| kind.get_child("color", ns))
+			.and_then(parse_floats);
+
+		match (light.get_attribute("id", None), color) {
+			(Some(id), Some(ref color)) if color.len() >= 3 => { lights.insert(id, (color[0], color[1], color[2])); },
+			(id, _) => eprintln!("warning[collada]: light {} has no readable color", id.unwrap_or(""))
+		}
+	}
+
+	lights
+}
+
+/// Name of the tag point for a light. Names that already follow the `light_R_G_B_i_j_k` convention are kept, others are
+/// replaced with one that encodes the color of the light. The meaning of `i`, `j` and `k` is unknown, so they are zero.
+fn light_name(node_name: &str, color: Option<(f32, f32, f32)>) -> String {
+	if node_name.starts_with("light_") && node_name.parse::<Light>().is_ok() {
+		return node_name.to_owned();
+	}
+
+	match color {
+		Some(color) => Light { color, unk: (0, 0, 0) }.to_string(),
+		None => node_name.to_owned()
+	}
+}
+
+/// The materials of the document, for finding the material of each primitive in the instanced geometry.
+struct Materials<'a> {
+	/// Name and texture name of each material, by id.
+	library: HashMap<&'a str, (String, String)>
+}
+
+impl<'a> Materials<'a> {
+	fn read(ns: Option<&'a str>, root: &'a Element) -> Self {
+		let effects = root.get_children("library_effects", ns)
+			.flat_map(|library| library.get_children("effect", ns))
+			.filter_map(|effect| effect.get_attribute("id", None).map(|id| (id, effect)))
+			.collect::<HashMap<_, _>>();
+
+		let images = root.get_children("library_images", ns)
+			.flat_map(|library| library.get_children("image", ns))
+			.filter_map(|image| image.get_attribute("id", None).map(|id| (id, image)))
+			.collect::<HashMap<_, _>>();
+
+		let mut library = HashMap::new();
+
+		for material in root.get_children("library_materials", ns).flat_map(|library| library.get_children("material", ns)) {
+			let id = match material.get_attribute("id", None) {
+				Some(id) => id,
+				None => continue
+			};
+
+			let effect = material.get_child("instance_effect", ns)
+				.and_then(|instance| instance.get_attribute("url", None))
+				.and_then(|url| effects.get(trim_hash(url)));
+
+			let texture_name = match effect.and_then(|effect| effect_image(ns, effect)) {
+				Some(image) => match images.get(&image as &str).and_then(|image| image.get_child("init_from", ns)) {
+					// COLLADA 1.5 wraps the path in a <ref>.
+					Some(init_from) => texture_name(&text(init_from.get_child("ref", ns).unwrap_or(init_from))),
+					None => {
+						eprintln!("warning[collada]: material {} uses the image {}, which does not exist", id, image);
+						String::new()
+					}
+				},
+				None => String::new()
+			};
+
+			library.insert(id, (material.get_attribute("name", None).unwrap_or(id).to_owned(), texture_name));
+		}
+
+		Materials { library }
+	}
+
+	/// Name and texture name of the material that an instance binds to the material symbol of a primitive.
+	fn resolve(&self, instance: &Instance, symbol: Option<&str>) -> (String, String) {
+		let symbol = match symbol {
+			Some(symbol) => symbol,
+			None => return (String::new(), String::new())
+		};
+
+		match instance.bindings.get(symbol) {
+			Some(target) => self.library.get(target as &str).cloned().unwrap_or_else(|| {
+				eprintln!("warning[collada]: node {} binds {} to the material {}, which does not exist", instance.node_name, symbol, target);
+				(target.clone(), String::new())
+			}),
+			None => {
+				eprintln!("warning[collada]: node {} does not bind the material {}, leaving it untextured", instance.node_name, symbol);
+				(symbol.to_owned(), String::new())
+			}
+		}
+	}
+}
+
+/// Id of the image used for the diffuse color of an effect, through the sampler and surface parameters in between.
+fn effect_image(ns: Option<&str>, effect: &Element) -> Option<String> {
+	let profile = effect.get_child("profile_COMMON", ns)?;
+	let technique = profile.get_child("technique", ns)?;
+
+	let texture = ["lambert", "phong", "blinn", "constant"].iter()
+		.filter_map(|&shading| technique.get_child(shading, ns))
+		.next()
+		.and_then(|shading| shading.get_child("diffuse", ns).or(shading.get_child("emission", ns)))
+		.and_then(|color| color.get_child("texture", ns))
+		.and_then(|texture| texture.get_attribute("texture", None))?;
+
+	let param = |sid: &str| profile.get_children("newparam", ns).find(|param| param.get_attribute("sid", None) == Some(sid));
+
+	// Some exporters skip the sampler and refer to the image directly.
+	let sampler = match param(texture).and_then(|param| param.get_child("sampler2D", ns)) {
+		Some(sampler) => sampler,
+		None => return Some(texture.to_owned())
+	};
+
+	// COLLADA 1.5 samplers refer to the image, while COLLADA 1.4 samplers refer to a surface that refers to the image.
+	if let Some(instance) = sampler.get_child("instance_image", ns) {
+		return instance.get_attribute("url", None).map(|url| trim_hash(url).to_owned());
+	}
+
+	let surface = text(sampler.get_child("source", ns)?);
+
+	param(surface.trim())
+		.and_then(|param| param.get_child("surface", ns))
+		.and_then(|surface| surface.get_child("init_from", ns))
+		.map(|init_from| text(init_from).trim().to_owned())
+}
+
+/// Walks the visual scene, collecting the geometry and tag points of every node, including nodes pulled in by <instance_node>.
+struct SceneGraph<'a> {
+	ns: Option<&'a str>,
+	/// Nodes that <instance_node> can refer to, by id.
+	library_nodes: HashMap<&'a str, &'a Element>,
+	/// Geometry morphed by each controller, by controller id.
+	controllers: HashMap<String, String>,
+	lights: HashMap<&'a str, (f32, f32, f32)>,
+	/// Nodes from the root of the visual scene down to the node being walked.
+	path: Vec<&'a Element>,
+	/// Ids of the library nodes currently being walked, which stops reference cycles.
+	instancing: Vec<&'a str>,
+	instances: Vec<Instance<'a>>,
+	tag_points: Vec<TagPoint<'a>>
+}
+
+impl<'a> SceneGraph<'a> {
+	fn walk(&mut self, node: &'a Element, parent_transform: Matrix4<f32>, parent: Option<&'a str>) -> Result<(), ConversionError> {
 		if node.get_attribute("type", None) == Some("JOINT") {
 			eprintln!("warning[collada]: unsupported node type JOINT, ignoring...");
-			continue;
+			return Ok(());
 		}
 
-		for element in node.children.iter().filter_map(|child| if let &xml::Xml::ElementNode(ref element) = child { Some(element) } else { None }) {
+		let node_name = node.get_attribute("name", None).or(node.get_attribute("id", None)).unwrap_or("");
+		let transform = parent_transform * node_transform(node, None)?;
+
+		self.path.push(node);
+
+		let mut has_geometry = false;
+		let mut has_contents = false;
+		let mut light = None;
+
+		for element in node.children.iter().filter_map(|child| if let xml::Xml::ElementNode(element) = child { Some(element) } else { None }) {
 			match &element.name as &str {
-				"asset" => (),
-				"lookat" | "matrix" | "rotate" | "scale" | "skew" | "translate" => {
-					eprintln!("warning[collada]: transformations on nodes are not supported yet (tried to use transformation type: {})...", element.name);
+				"instance_camera" => {
+					eprintln!("warning[collada]: Ignoring instance_camera");
+					has_contents = true;
 				},
-				"instance_camera" => eprintln!("warning[collada]: Ignoring instance_camera"),
-				"instance_controller" => eprintln!("warning[collada]: Ignoring instance_controller"),
-				"instance_geometry" => {
-					let object_id = if let Some(url) = element.get_attribute("url", None) {
+				"instance_controller" | "instance_geometry" => {
+					let url = if let Some(url) = element.get_attribute("url", None) {
 						trim_hash(url)
 					} else {
-						eprintln!("warning[collada]: degenerate <instance_geometry> is missing a url tag");
+						eprintln!("warning[collada]: degenerate <{}> is missing a url tag", element.name);
 						continue;
 					};
 
-					root_geometry.push(object_id.to_owned());
+					// Morph controllers stand in for the geometry they morph, which already has the controller attached.
+					let object_id = if element.name == "instance_controller" {
+						match self.controllers.get(url) {
+							Some(object_id) => object_id as &str,
+							None => {
+								eprintln!("warning[collada]: Ignoring instance_controller of {}, only morph controllers are supported", url);
+								has_contents = true;
+								continue;
+							}
+						}
+					} else {
+						url
+					};
+
+					let bindings = element.get_child("bind_material", self.ns)
+						.and_then(|bind_material| bind_material.get_child("technique_common", self.ns))
+						.into_iter()
+						.flat_map(|technique| technique.get_children("instance_material", self.ns))
+						.filter_map(|binding| match (binding.get_attribute("symbol", None), binding.get_attribute("target", None)) {
+							(Some(symbol), Some(target)) => Some((symbol.to_owned(), trim_hash(target).to_owned())),
+							_ => None
+						})
+						.collect();
+
+					self.instances.push(Instance {
+						node_name: node_name.to_owned(),
+						object_id: object_id.to_owned(),
+						transform,
+						parent: parent.map(str::to_owned),
+						path: self.path.clone(),
+						bindings
+					});
+
+					has_geometry = true;
+					has_contents = true;
+				},
+				"instance_light" => {
+					let color = element.get_attribute("url", None).and_then(|url| self.lights.get(trim_hash(url)).cloned());
+
+					if color.is_none() {
+						eprintln!("warning[collada]: light in node {} does not exist or has no color, keeping the node name", node_name);
+					}
+
+					light = Some(color);
+				},
+				"node" | "instance_node" => has_contents = true,
+				_ => ()
+			}
+		}
+
+		// Anything further down is nested under this node, if it has geometry.
+		let parent = if has_geometry { Some(split_lod(node_name).0) } else { parent };
+
+		// Lights are always tag points, while other nodes only are if they are empty.
+		let tag_name = match light {
+			Some(color) => Some(light_name(node_name, color)),
+			None if !has_contents => Some(node_name.to_owned()),
+			None => None
+		};
+
+		if let Some(name) = tag_name {
+			self.tag_points.push(TagPoint {
+				name,
+				parent: parent.map(str::to_owned),
+				path: self.path.clone()
+			});
+		}
+
+		for element in node.children.iter().filter_map(|child| if let xml::Xml::ElementNode(element) = child { Some(element) } else { None }) {
+			match &element.name as &str {
+				"node" => self.walk(element, transform, parent)?,
+				"instance_node" => {
+					let id = match element.get_attribute("url", None) {
+						Some(url) => trim_hash(url),
+						None => {
+							eprintln!("warning[collada]: degenerate <instance_node> is missing a url tag");
+							continue;
+						}
+					};
+
+					let instanced = *self.library_nodes.get(id).ok_or_else(|| ConversionError::collada(format!("COLLADA/library_nodes/node[@id='{}']", id), "the node named in <instance_node> does not exist"))?;
+					let id = instanced.get_attribute("id", None).unwrap_or("");
+
+					if self.instancing.contains(&id) {
+						eprintln!("warning[collada]: node {} instances itself, ignoring the cycle", id);
+						continue;
+					}
+
+					self.instancing.push(id);
+					self.walk(instanced, transform, parent)?;
+					self.instancing.pop();
 				},
-				"instance_light" => eprintln!("warning[collada]: Lights are unsupported"),
-				"instance_node" => eprintln!("warning[collada]: Ignoring instance_node"),
-				"node" => eprintln!("warning[collada]: Nested nodes are unsupported"),
 				_ => ()
 			}
 		}
+
+		self.path.pop();
+
+		Ok(())
 	}
+}
 
-	// Needed information extracted. Now begin conversion.
+/// Indices of the position, texture coordinates and normal of a corner of a triangle.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// The geometry of a <mesh>. Every source its primitives use is read into the array for its semantic, so that the
+/// corners of every primitive index the same arrays.
+struct Mesh<'a> {
+	positions: Vec<Vector3<f32>>,
+	normals: Vec<Vector3<f32>>,
+	texture_coordinates: Vec<Vector2<f32>>,
+	primitives: Vec<Primitive<'a>>
+}
 
-	if root_geometry.len() == 0 {
-		panic!("No root geometry!");
-	} else if root_geometry.len() > 1 {
-		eprintln!("warning[collada]: ignoring additional root geometry for now, submodels are not supported yet");
+/// The triangles of a <triangles> or <polylist> element, along with the material symbol that <bind_material> binds.
+struct Primitive<'a> {
+	material: Option<&'a str>,
+	triangles: Vec<(Corner, Corner, Corner)>
+}
+
+/// Reads the mesh of every geometry in the document, by id. Other kinds of geometry, such as splines, are skipped.
+fn read_meshes<'a>(ns: Option<&'a str>, root: &'a Element) -> Result<HashMap<&'a str, Mesh<'a>>, ConversionError> {
+	let mut meshes = HashMap::new();
+
+	for geometry in root.get_children("library_geometries", ns).flat_map(|library| library.get_children("geometry", ns)) {
+		if let (Some(id), Some(mesh)) = (geometry.get_attribute("id", None), geometry.get_child("mesh", ns)) {
+			meshes.insert(id, Mesh::read(ns, id, mesh)?);
+		}
 	}
 
-	let root_name = &root_geometry[0];
+	Ok(meshes)
+}
+
+impl<'a> Mesh<'a> {
+	/// Reads every <triangles> and <polylist> element of a mesh. Polygons are split into fans of triangles, while points
+	/// and lines are skipped.
+	fn read(ns: Option<&'a str>, id: &str, mesh: &'a Element) -> Result<Self, ConversionError> {
+		let path = format!("COLLADA/library_geometries/geometry[@id='{}']/mesh", id);
+
+		let vertices = mesh.get_child("vertices", ns).ok_or_else(|| ConversionError::collada(path.clone(), "missing <vertices>"))?;
+		let vertices_id = vertices.get_attribute("id", None).unwrap_or("");
+
+		let mut result = Mesh { positions: Vec::new(), normals: Vec::new(), texture_coordinates: Vec::new(), primitives: Vec::new() };
+		// Where each source that was read starts in the array for its semantic, and how many elements it has.
+		let mut ranges = HashMap::new();
+
+		for element in mesh.children.iter().filter_map(|child| if let xml::Xml::ElementNode(element) = child { Some(element) } else { None }) {
+			let polylist = match &element.name as &str {
+				"triangles" => false,
+				"polylist" => true,
+				"polygons" | "trifans" | "tristrips" => {
+					eprintln!("warning[collada]: {} uses <{}>, which is not supported, skipping it", id, element.name);
+					continue;
+				},
+				_ => continue
+			};
+
+			let primitive_path = format!("{}/{}", path, element.name);
+
+			// Semantic, offset within the indices of a corner, and source id of each input.
+			let mut inputs = Vec::new();
+
+			for input in element.get_children("input", ns) {
+				let offset = input.get_attribute("offset", None).and_then(|offset| offset.parse::<usize>().ok())
+					.ok_or_else(|| ConversionError::collada(format!("{}/input", primitive_path), "missing or invalid \"offset\" attribute"))?;
+
+				inputs.push((input.get_attribute("semantic", None).unwrap_or(""), offset, trim_hash(input.get_attribute("source", None).unwrap_or(""))));
+			}
+
+			let stride = inputs.iter().map(|&(_, offset, _)| offset + 1).max().unwrap_or(0);
+
+			let (vertex_offset, vertex_source) = inputs.iter().find(|&&(semantic, _, _)| semantic == "VERTEX").map(|&(_, offset, source)| (offset, source))
+				.ok_or_else(|| ConversionError::collada(primitive_path.clone(), "missing the VERTEX input"))?;
+
+			if vertex_source != vertices_id {
+				return Err(ConversionError::collada(primitive_path, format!("the VERTEX input refers to {} instead of the <vertices> of the mesh", vertex_source)));
+			}
+
+			// Normals and texture coordinates can also be inputs of <vertices>, which share the index of the position.
+			let channel = |semantic: &str| inputs.iter().find(|&&(input, _, _)| input == semantic).map(|&(_, offset, source)| (offset, source))
+				.or_else(|| get_input(ns, vertices, semantic).and_then(|input| input.get_attribute("source", None)).map(|source| (vertex_offset, trim_hash(source))));
+
+			let position = match channel("POSITION") {
+				Some((offset, source)) => (offset, result.read_source(ns, mesh, &mut ranges, "POSITION", source)?),
+				None => return Err(ConversionError::collada(format!("{}/vertices", path), "missing the POSITION input"))
+			};
+
+			let texture = match channel("TEXCOORD") {
+				Some((offset, source)) => Some((offset, result.read_source(ns, mesh, &mut ranges, "TEXCOORD", source)?)),
+				None => None
+			};
+
+			let normal = match channel("NORMAL") {
+				Some((offset, source)) => Some((offset, result.read_source(ns, mesh, &mut ranges, "NORMAL", source)?)),
+				None => None
+			};
+
+			let indices = match element.get_child("p", ns) {
+				Some(p) => parse_indices(p).ok_or_else(|| ConversionError::collada(format!("{}/p", primitive_path), "indices have to be whole numbers"))?,
+				None => Vec::new()
+			};
+
+			if indices.len() % stride != 0 {
+				return Err(ConversionError::collada(format!("{}/p", primitive_path), format!("{} indices do not split into corners of {} indices", indices.len(), stride)));
+			}
+
+			let index = |indices: &[usize], (offset, (start, len)): (usize, (usize, usize)), name: &str| match indices[offset] {
+				index if index < len => Ok(start + index),
+				index => Err(ConversionError::collada(format!("{}/p", primitive_path), format!("{} index {} is out of range for {} elements", name, index, len)))
+			};
+
+			let corners = indices.chunks(stride).map(|indices| Ok((
+				index(indices, position, "position")?,
+				match texture { Some(texture) => Some(index(indices, texture, "texture coordinate")?), None => None },
+				match normal { Some(normal) => Some(index(indices, normal, "normal")?), None => None }
+			))).collect::<Result<Vec<Corner>, ConversionError>>()?;
 
-	let object = objects.get(root_name).expect("geometry library missing root geometry");
-	let object_frames = morph_links.get(root_name)
-		.map(|vec| vec.iter()
-			.map(|name| objects.get(name).expect("geometry library missing geometry frame").clone()).collect::<Vec<Object>>()
-		).unwrap_or_else(Vec::new);
+			let mut triangles = Vec::new();
 
-	let mut failed_index = None;
+			if polylist {
+				let counts = match element.get_child("vcount", ns) {
+					Some(vcount) => parse_indices(vcount).ok_or_else(|| ConversionError::collada(format!("{}/vcount", primitive_path), "vertex counts have to be whole numbers"))?,
+					None => Vec::new()
+				};
+
+				if counts.iter().try_fold(0usize, |sum, &count| sum.checked_add(count)) != Some(corners.len()) {
+					return Err(ConversionError::collada(format!("{}/vcount", primitive_path), format!("the vertex counts do not add up to the {} corners", corners.len())));
+				}
 
-	for (index, frame) in object_frames.iter().enumerate() {
-		if object.vertices.len() != frame.vertices.len() || object.normals.len() != frame.normals.len() || object.tex_vertices.len() != frame.tex_vertices.len() {
-			failed_index = Some(index);
-			break;
+				let mut start = 0;
+
+				for &count in &counts {
+					for corner in 2..count {
+						triangles.push((corners[start], corners[start + corner - 1], corners[start + corner]));
+					}
+
+					start += count;
+				}
+			} else {
+				if corners.len() % 3 != 0 {
+					return Err(ConversionError::collada(format!("{}/p", primitive_path), format!("{} corners do not split into triangles", corners.len())));
+				}
+
+				triangles.extend(corners.chunks(3).map(|corners| (corners[0], corners[1], corners[2])));
+			}
+
+			result.primitives.push(Primitive { material: element.get_attribute("material", None), triangles });
 		}
 
-		let base = &object.geometry;
-		let frame = &frame.geometry;
+		Ok(result)
+	}
 
-		if base.len() != frame.len() {
-			failed_index = Some(index);
-			break;
+	/// Reads a source of the mesh into the array for a semantic, unless it was read before. Returns where the source starts
+	/// in the array and how many elements it has.
+	fn read_source(&mut self, ns: Option<&str>, mesh: &'a Element, ranges: &mut HashMap<(&'a str, &'a str), (usize, usize)>, semantic: &'a str, id: &'a str) -> Result<(usize, usize), ConversionError> {
+		if let Some(&range) = ranges.get(&(semantic, id)) {
+			return Ok(range);
 		}
 
-		if let Some(_) = base.iter().zip(frame.iter()).find(|pair| !compare_geometry(&pair.0.shapes, &pair.1.shapes)) {
-			failed_index = Some(index);
-			break;
+		let source = mesh.get_children("source", ns).find(|source| source.get_attribute("id", None) == Some(id))
+			.ok_or_else(|| ConversionError::collada(format!("COLLADA//source[@id='{}']", id), format!("the {} source does not exist", semantic)))?;
+
+		let elements = read_source::<f32>(ns, source)?;
+		let width = if semantic == "TEXCOORD" { 2 } else { 3 };
+
+		if elements.iter().any(|element| element.len() < width) {
+			return Err(ConversionError::collada(format!("COLLADA//source[@id='{}']", id), format!("{} needs {} values per element", semantic, width)));
 		}
+
+		let start = match semantic {
+			"POSITION" => extend(&mut self.positions, elements.iter().map(|element| Vector3::new(element[0], element[1], element[2]))),
+			"NORMAL" => extend(&mut self.normals, elements.iter().map(|element| Vector3::new(element[0], element[1], element[2]))),
+			_ => extend(&mut self.texture_coordinates, elements.iter().map(|element| Vector2::new(element[0], element[1])))
+		};
+
+		ranges.insert((semantic, id), (start, elements.len()));
+
+		Ok((start, elements.len()))
 	}
+}
+
+/// Appends to a vector, returning where the new elements start.
+fn extend<T, I>(vector: &mut Vec<T>, elements: I) -> usize where I: Iterator<Item = T> {
+	let start = vector.len();
+	vector.extend(elements);
+	start
+}
+
+/// For each level of detail, the base geometry, its morph and the geometry of the morph targets.
+type Source<'a> = (&'a Mesh<'a>, Option<&'a Morph>, Vec<&'a Mesh<'a>>);
+
+fn read_sources<'a>(meshes: &'a HashMap<&'a str, Mesh<'a>>, morph_links: &'a HashMap<String, Morph>, lods: &[&Instance]) -> Result<Vec<Source<'a>>, ConversionError> {
+	let mut sources = Vec::with_capacity(lods.len());
+
+	for instance in lods {
+		let lod_name = &instance.object_id as &str;
+
+		let mesh = meshes.get(lod_name).ok_or_else(|| ConversionError::collada(format!("COLLADA/library_geometries/geometry[@id='{}']", lod_name), "geometry library is missing the root geometry"))?;
 
-	if let Some(failed_index) = failed_index {
-		panic!("error[collada]: index {} in the morph target sequence uses different geometry", failed_index);
+		let morph = morph_links.get(lod_name);
+		let mut targets = Vec::new();
+
+		for name in morph.iter().flat_map(|morph| morph.targets.iter()) {
+			targets.push(meshes.get(name as &str).ok_or_else(|| ConversionError::collada(format!("COLLADA/library_geometries/geometry[@id='{}']", name), "geometry library is missing a morph target"))?);
+		}
+
+		if let Some(failed_index) = check_frames(mesh, &targets) {
+			return Err(ConversionError::collada("COLLADA/library_controllers/controller/morph/targets", format!("index {} in the morph target sequence of {} uses different geometry", failed_index, lod_name)));
+		}
+
+		sources.push((mesh, morph, targets));
 	}
 
-	let mut associations = Vec::new();
-	let mut reverse = HashMap::new();
-	let mut triangles = Vec::new();
+	Ok(sources)
+}
+
+/// Whether the animations move any level of detail or tag point of a model, or change the weights of its morphs.
+fn animates(animations: &Animations, lods: &[&Instance], sources: &[Source], tag_points: &[&TagPoint]) -> bool {
+	lods.iter().any(|instance| animations.animates_path(&instance.path))
+		|| sources.iter().any(|&(_, morph, _)| morph.map(|morph| animations.animates_morph(morph)).unwrap_or(false))
+		|| tag_points.iter().any(|tag_point| animations.animates_path(&tag_point.path))
+}
 
-	// Note: We make the last entry of each vertex component array the zero/invalid entry for missings
-	let invalid_texture_index = object.tex_vertices.len();
-	let invalid_normal_index = object.normals.len();
+/// How the frames of every model in the scene are made.
+struct Sampling<'a> {
+	animations: &'a Animations,
+	frame_rate: f32,
+	/// Whether the frames are samples of the animations, rather than the base geometry followed by each morph target.
+	animated: bool,
+	frame_count: usize
+}
 
-	{
-		let mut dedup_vertex = |vertex: VTNIndex| {
-			let vertex = (
-				vertex.0,
-				vertex.1.unwrap_or(invalid_texture_index),
-				vertex.2.unwrap_or(invalid_normal_index)
-			);
+/// Builds a model out of the geometry for each level of detail, along with the morph targets of each.
+/// The geometry of each level of detail is moved by the transform of the node that instances it, and each material
+/// bound to it becomes a material of the model.
+///
+/// In animated scenes, each frame is a sample of the animation, with the morph targets blended by their animated
+/// weights. Otherwise, the base geometry is the first frame and each morph target is another.
+fn build_model(materials: &Materials, lods: &[&Instance], sources: &[Source], tag_points: &[&TagPoint], sampling: &Sampling) -> Result<V2, ConversionError> {
+	let Sampling { animations, frame_rate, animated, frame_count } = *sampling;
 
-			*reverse.entry(vertex).or_insert_with(|| {
-				let index = associations.len();
+	// Each material has its own range of the vertex buffer, so vertices are collected per material first.
+	let mut builders = Vec::new();
 
-				associations.push(vertex);
+	for (lod, &(mesh, _, _)) in sources.iter().enumerate() {
+		// Mirroring transforms turn the faces inside out unless their winding is reversed as well.
+		let mirrored = lods[lod].transform.determinant() < 0.0;
 
-				index
-			})
-		};
+		// Note: We make the last entry of each vertex component array the zero/invalid entry for missings
+		let invalid_texture_index = mesh.texture_coordinates.len();
+		let invalid_normal_index = mesh.normals.len();
 
-		for geometry in &object.geometry {
-			for shape in &geometry.shapes {
-				match shape {
-					&Shape::Triangle(a, b, c) => {
-						triangles.push((
-							dedup_vertex(a) as u32,
-							dedup_vertex(b) as u32,
-							dedup_vertex(c) as u32
-						));
-					},
-					_ => () // Lines / points unsupported
-				}
+		let vertex = |vertex: Corner| (
+			lod,
+			vertex.0,
+			vertex.1.unwrap_or(invalid_texture_index),
+			vertex.2.unwrap_or(invalid_normal_index)
+		);
+
+		for primitive in mesh.primitives.iter().filter(|primitive| !primitive.triangles.is_empty()) {
+			let builder = MaterialBuilder::select(&mut builders, materials.resolve(lods[lod], primitive.material), lod);
+
+			for &(a, b, c) in &primitive.triangles {
+				let triangle = (builder.dedup(vertex(a)), builder.dedup(vertex(b)), builder.dedup(vertex(c)));
+
+				builder.push(lod, triangle, mirrored);
 			}
 		}
+
+		let triangle_count = builders.iter().map(|builder| builder.triangle_count(lod)).sum::<usize>();
+		let vertex_count = builders.iter().map(MaterialBuilder::vertex_count).sum::<usize>();
+
+		eprintln!("{} triangles with {} flattened vertices (from: {} position, {} tex, {} normal)", triangle_count, vertex_count, mesh.positions.len(), mesh.texture_coordinates.len(), mesh.normals.len());
 	}
 
-	println!("{} triangles with {} flattened vertices (from: {} position, {} tex, {} normal)", triangles.len(), associations.len(), object.vertices.len(), object.tex_vertices.len(), object.normals.len());
+	let (associations, lod_levels, model_materials) = layout(builders, sources.len());
 
-	let mut frames = Vec::with_capacity(1 + object_frames.len());
+	let mut frames = Vec::with_capacity(frame_count);
+	let mut center = Point3 { x: 0.0, y: 0.0, z: 0.0 };
 
-	// TODO: Tag Points
-	let (center, frame0) = extract_frame(&object, &associations, vec![]);
+	for frame_index in 0..frame_count {
+		let time = animations.start + frame_index as f32 / frame_rate;
 
-	frames.push(frame0);
+		let mut blends = Vec::with_capacity(sources.len());
 
-	for additional_frame in &object_frames {
-		frames.push(extract_frame(additional_frame, &associations, vec![]).1);
-	}
+		for (&(base, morph, ref targets), instance) in sources.iter().zip(lods.iter()) {
+			let relative = morph.map(|morph| morph.relative).unwrap_or(false);
 
+			blends.push(if animated {
+				let weights = morph.map(|morph| animations.morph_weights(morph, time)).unwrap_or_else(Vec::new);
 
-	v2::V2 {
-		center,
-		materials: vec![v2::Material {
-			name: "".to_string(),
-			texture: 0,
-			triangles: vec![
-				v2::TriangleSelection {
-					offset: 0,
-					len: triangles.len() as u32
+				Blend {
+					base,
+					targets: targets.iter().cloned().zip(weights).collect(),
+					relative,
+					transform: world_transform(&instance.path, Some((animations, time)))?
+				}
+			} else {
+				Blend {
+					base,
+					targets: frame_index.checked_sub(1).and_then(|target| targets.get(target)).map(|&target| (target, 1.0)).into_iter().collect(),
+					relative,
+					transform: instance.transform
 				}
-			],
-			vertex_offset: 0,
-			vertex_count: associations.len() as u32,
-			texture_name: "".to_string()
-		}],
-		lod_levels: vec![
-			triangles
-		],
-		tag_points: vec![],
+			});
+		}
+
+		let tag_positions = tag_points.iter().map(|tag_point| tag_point.position(animations, time)).collect::<Result<Vec<_>, _>>()?;
+
+		let (frame_center, frame) = extract_frame(&blends, &associations, tag_positions);
+
+		if frame_index == 0 {
+			center = frame_center;
+		}
+
+		frames.push(frame);
+	}
+
+	let mut model = v2::V2 {
+		center,
+		materials: model_materials,
+		lod_levels,
+		tag_points: tag_points.iter().map(|tag_point| tag_point.name.clone()).collect(),
 		frames
+	};
+
+	// Each level of detail is its own geometry, so the vertices they have in common are only merged once they are built.
+	merge_vertices(&mut model);
+
+	Ok(model)
+}
+
+/// The morph targets of a piece of geometry, from a morph controller.
+struct Morph {
+	targets: Vec<String>,
+	/// Whether the targets are offsets from the base geometry (RELATIVE), instead of complete meshes (NORMALIZED).
+	relative: bool,
+	/// Preset weight of each target, used unless the weights are animated.
+	weights: Vec<f32>,
+	/// Ids that animations of the weights can target.
+	weight_ids: Vec<String>
+}
+
+/// Geometry of one level of detail in a frame: the base geometry with weighted morph targets on top, moved by a transform.
+struct Blend<'o> {
+	base: &'o Mesh<'o>,
+	targets: Vec<(&'o Mesh<'o>, f32)>,
+	relative: bool,
+	transform: Matrix4<f32>
+}
+
+impl<'o> Blend<'o> {
+	/// Blends the position, normal and texture coordinates of a vertex. NORMALIZED morphs scale the base geometry down by
+	/// the total weight of the targets, while RELATIVE morphs keep it as is and only add the weighted offsets.
+	fn vertex(&self, position: usize, texture: usize, normal: usize) -> (Point3<f32>, Vector3<f32>, Point2<f32>) {
+		// A missing normal offset means the normal does not change, unlike a missing normal on a complete mesh.
+		let read = |mesh: &Mesh, offset: bool| (
+			mesh.positions[position],
+			mesh.normals.get(normal).cloned().unwrap_or(if offset { Vector3::new(0.0, 0.0, 0.0) } else { Vector3::new(1.0, 0.0, 0.0) }),
+			mesh.texture_coordinates.get(texture).cloned().unwrap_or(Vector2::new(0.0, 0.0))
+		);
+
+		let base_weight = if self.relative { 1.0 } else { 1.0 - self.targets.iter().map(|&(_, weight)| weight).sum::<f32>() };
+
+		let (base_position, base_normal, base_texture) = read(self.base, false);
+
+		let mut position = base_position * base_weight;
+		let mut normal = base_normal * base_weight;
+		// Texture coordinates are not offset by RELATIVE targets.
+		let mut texture = if self.relative { base_texture } else { base_texture * base_weight };
+
+		for &(target, weight) in &self.targets {
+			let (target_position, target_normal, target_texture) = read(target, self.relative);
+
+			position += target_position * weight;
+			normal += target_normal * weight;
+
+			if !self.relative {
+				texture += target_texture * weight;
+			}
+		}
+
+		(Point3::from_vec(position), normal, Point2::from_vec(texture))
 	}
 }
 
-fn extract_frame(from: &Object, indices: &[(usize, usize, usize)], tag_points: Vec<Point3<f32>>) -> (Point3<f32>, v2::Frame) {
-	let transformation = Matrix4::from_angle_x(Deg(90.0));
+/// Returns the index of the first frame that does not share the topology of the base geometry.
+fn check_frames(mesh: &Mesh, targets: &[&Mesh]) -> Option<usize> {
+	targets.iter().position(|target| {
+		mesh.positions.len() != target.positions.len()
+			|| mesh.normals.len() != target.normals.len()
+			|| mesh.texture_coordinates.len() != target.texture_coordinates.len()
+			|| mesh.primitives.len() != target.primitives.len()
+			|| mesh.primitives.iter().zip(&target.primitives).any(|(base, target)| base.triangles != target.triangles)
+	})
+}
+
+/// Builds a frame out of the blended geometry of each level of detail, moved by its transform and then rotated from
+/// Y-up to Z-up.
+fn extract_frame(from: &[Blend], indices: &[(usize, usize, usize, usize)], tag_points: Vec<Point3<f32>>) -> (Point3<f32>, v2::Frame) {
+	let rotation = Matrix4::from_angle_x(Deg(90.0));
+
+	let transforms = from.iter().map(|blend| {
+		let transformation = rotation * blend.transform;
+
+		(transformation, normal_matrix(transformation))
+	}).collect::<Vec<_>>();
 
 	let mut vertices = Vec::with_capacity(indices.len());
 	let mut center_builder = collider::CenterBuilder::begin();
 
-	for &(position, texture, normal) in indices {
-		let position = from.vertices[position];
-		let texture = from.tex_vertices.get(texture).unwrap_or(&TVertex { x: 0.0, y: 0.0 });
-		let normal = from.normals.get(normal).unwrap_or(&NVertex { x: 1.0, y: 0.0, z: 0.0 });
-
-		let normal = Vector3 { x: normal.x as f32, y: normal.y as f32, z: normal.z as f32 };
-		let position = Point3 { x: position.x as f32, y: position.y as f32, z: position.z as f32 };
+	for &(lod, position, texture, normal) in indices {
+		let (transformation, normal_transformation) = transforms[lod];
+		let (position, normal, texture) = from[lod].vertex(position, texture, normal);
 
 		let vertex = v2::Vertex {
 			position: Point3::from_homogeneous(transformation * position.to_homogeneous()),
-			normal: (transformation * normal.normalize().extend(0.0)).truncate(),
-			texture: Point2 { x: texture.x as f32, y: 1.0 - texture.y as f32 },
+			normal: (normal_transformation * normal).normalize(),
+			texture: Point2 { x: texture.x, y: 1.0 - texture.y },
 		};
 
 		center_builder.update(vertex.position);
@@ -251,31 +974,433 @@ fn extract_frame(from: &Object, indices: &[(usize, usize, usize)], tag_points: V
 	(center, v2::Frame::from_vertices(vertices, tag_points, center))
 }
 
-// Utilities for COLLADA (Mostly taken from private methods in piston_collada)
+/// Accumulated transform of a node and all of its ancestors.
+fn world_transform(path: &[&Element], animated: Option<(&Animations, f32)>) -> Result<Matrix4<f32>, ConversionError> {
+	let mut transform = Matrix4::identity();
+
+	for node in path {
+		transform = transform * node_transform(node, animated)?;
+	}
+
+	Ok(transform)
+}
+
+/// Evaluates the transformation elements of a node in document order, which is the order COLLADA composes them in.
+/// When given animations and a time, animated elements take their values from the animation at that time.
+fn node_transform(node: &Element, animated: Option<(&Animations, f32)>) -> Result<Matrix4<f32>, ConversionError> {
+	let mut transform = Matrix4::identity();
+
+	for element in node.children.iter().filter_map(|child| if let xml::Xml::ElementNode(element) = child { Some(element) } else { None }) {
+		let expected = match &element.name as &str {
+			"translate" | "scale" => 3,
+			"rotate" => 4,
+			"skew" => 7,
+			"lookat" => 9,
+			"matrix" => 16,
+			_ => continue
+		};
+
+		let path = || format!("COLLADA/library_visual_scenes/visual_scene/node[@name='{}']/{}", node.get_attribute("name", None).or(node.get_attribute("id", None)).unwrap_or(""), element.name);
+
+		let mut values = parse_floats(element).ok_or_else(|| ConversionError::collada(path(), "contains something other than numbers"))?;
+
+		if values.len() != expected {
+			return Err(ConversionError::collada(path(), format!("has {} values instead of {}", values.len(), expected)));
+		}
+
+		if let (Some((animations, time)), Some(id), Some(sid)) = (animated, node.get_attribute("id", None), element.get_attribute("sid", None)) {
+			let target = format!("{}/{}", id, sid);
+
+			for channel in animations.get(&target) {
+				if !channel.apply(&mut values, time) {
+					eprintln!("warning[collada]: animation of {} does not fit the element it targets, ignoring it", target);
+				}
+			}
+		}
+
+		let v = &values;
+
+		transform = transform * match &element.name as &str {
+			"translate" => Matrix4::from_translation(Vector3::new(v[0], v[1], v[2])),
+			"scale" => Matrix4::from_nonuniform_scale(v[0], v[1], v[2]),
+			"rotate" => {
+				let axis = Vector3::new(v[0], v[1], v[2]);
+
+				if axis.magnitude2() == 0.0 {
+					eprintln!("warning[collada]: <rotate> with a zero axis in {}, ignoring it", path());
+					continue;
+				}
+
+				Matrix4::from_axis_angle(axis.normalize(), Deg(v[3]))
+			},
+			"skew" => skew(Deg(v[0]), Vector3::new(v[1], v[2], v[3]), Vector3::new(v[4], v[5], v[6])),
+			"lookat" => {
+				// <lookat> places the node at the eye, looking at the interest point, which is the inverse of a view matrix.
+				let view = Matrix4::look_at(Point3::new(v[0], v[1], v[2]), Point3::new(v[3], v[4], v[5]), Vector3::new(v[6], v[7], v[8]));
+
+				match view.invert() {
+					Some(transform) => transform,
+					None => {
+						eprintln!("warning[collada]: degenerate <lookat> in {}, ignoring it", path());
+						continue;
+					}
+				}
+			},
+			// <matrix> is written row by row, while cgmath takes columns.
+			"matrix" => Matrix4::from_cols(
+				Vector4::new(v[0], v[4], v[8], v[12]),
+				Vector4::new(v[1], v[5], v[9], v[13]),
+				Vector4::new(v[2], v[6], v[10], v[14]),
+				Vector4::new(v[3], v[7], v[11], v[15])
+			),
+			_ => unreachable!()
+		};
+	}
+
+
+	Ok(transform)
+}
+
+/// The RenderMan style skew used by COLLADA: points slide along `translation` by an amount proportional to how far they
+/// lie along the part of `rotation` perpendicular to it, such that `rotation` itself ends up rotated by `angle`.
+fn skew(angle: Deg<f32>, rotation: Vector3<f32>, translation: Vector3<f32>) -> Matrix4<f32> {
+	if translation.magnitude2() == 0.0 {
+		return Matrix4::identity();
+	}
+
+	let n2 = translation.normalize();
+	let perpendicular = rotation - n2 * rotation.dot(n2);
+
+	if perpendicular.magnitude2() == 0.0 {
+		return Matrix4::identity();
+	}
+
+	let n1 = perpendicular.normalize();
+
+	let (an1, an2) = (rotation.dot(n1), rotation.dot(n2));
+	let (sin, cos) = Rad::from(angle).0.sin_cos();
+
+	let rx = an1 * cos - an2 * sin;
+	let ry = an1 * sin + an2 * cos;
+
+	if rx <= 0.0 {
+		eprintln!("warning[collada]: <skew> angle is too large, ignoring it");
+		return Matrix4::identity();
+	}
+
+	let alpha = ry / rx - an2 / an1;
+
+	// I + alpha * n2 * n1^T, column by column.
+	let column = |axis: usize, unit: Vector3<f32>| (unit + n2 * (alpha * n1[axis])).extend(0.0);
+
+	Matrix4::from_cols(
+		column(0, Vector3::unit_x()),
+		column(1, Vector3::unit_y()),
+		column(2, Vector3::unit_z()),
+		Vector4::unit_w()
+	)
+}
+
+/// Animation channels of the document, keyed by what they target: `<node id>/<sid>` for transformation elements, and
+/// the id of the weight source or its array for morph weights.
+struct Animations {
+	channels: HashMap<String, Vec<Channel>>,
+	/// Time of the first key of any channel, in seconds.
+	start: f32,
+	/// Time of the last key of any channel, in seconds.
+	end: f32
+}
+
+impl Animations {
+	fn get(&self, target: &str) -> &[Channel] {
+		self.channels.get(target).map(Vec::as_slice).unwrap_or(&[])
+	}
+
+	/// Number of frames needed to cover every key at the given frame rate, at most `MAX_FRAMES`.
+	fn frame_count(&self, frame_rate: f32) -> Result<usize, ConversionError> {
+		let intervals = ((self.end - self.start) * frame_rate).round();
+
+		// NaN durations are rejected along with the long ones.
+		if intervals.is_nan() || intervals >= MAX_FRAMES as f32 {
+			return Err(ConversionError::collada("COLLADA/library_animations", format!(
+				"the animation takes {} seconds, which is more than {} frames at {} frames per second, use a lower --frame-rate", self.end - self.start, MAX_FRAMES, frame_rate
+			)));
+		}
+
+		Ok(intervals as usize + 1)
+	}
 
-fn compare_geometry(base: &[Shape], frame: &[Shape]) -> bool {
-	if base.len() != frame.len() {
-		return false;
+	/// Whether any node along the path has an animated transformation element.
+	fn animates_path(&self, path: &[&Element]) -> bool {
+		path.iter().filter_map(|node| node.get_attribute("id", None)).any(|id| {
+			self.channels.keys().any(|target| target.starts_with(id) && target[id.len()..].starts_with('/'))
+		})
+	}
+
+	fn animates_morph(&self, morph: &Morph) -> bool {
+		morph.weight_ids.iter().any(|id| self.channels.contains_key(id))
 	}
 
-	if let Some(_) = base.iter().zip(frame.iter()).find(|pair| !compare_shape(*pair.0, *pair.1)) {
-		return false;
+	/// Weights of the morph targets at the given time, starting from the preset weights.
+	fn morph_weights(&self, morph: &Morph, time: f32) -> Vec<f32> {
+		let mut weights = morph.weights.clone();
+
+		for id in &morph.weight_ids {
+			for channel in self.get(id) {
+				if !channel.apply(&mut weights, time) {
+					eprintln!("warning[collada]: animation of {} does not fit the morph weights, ignoring it", id);
+				}
+			}
+		}
+
+		weights
 	}
+}
 
-	true
+struct Channel {
+	/// Index of the single value animated by the channel, or `None` if it animates the whole element.
+	component: Option<usize>,
+	/// Time of each key, in seconds.
+	times: Vec<f32>,
+	/// Values of the element or component at each key.
+	keys: Vec<Vec<f32>>,
+	/// Whether values jump from key to key instead of changing linearly in between.
+	step: bool
 }
 
-fn compare_shape(base: Shape, frame: Shape) -> bool {
-	match (base, frame) {
-		(Shape::Point(a), Shape::Point(b)) => a==b,
-		(Shape::Line(a, a1), Shape::Line(b, b1)) => a==b && a1==b1,
-		(Shape::Triangle(a, a1, a2), Shape::Triangle(b, b1, b2)) => a==b && a1==b1 && a2==b2,
-		_ => false
+impl Channel {
+	fn sample(&self, time: f32) -> Vec<f32> {
+		let next = match self.times.iter().position(|&key_time| key_time > time) {
+			Some(0) => return self.keys[0].clone(),
+			Some(next) => next,
+			None => return self.keys.last().cloned().unwrap_or_else(Vec::new)
+		};
+
+		let (previous_key, next_key) = (&self.keys[next - 1], &self.keys[next]);
+
+		if self.step {
+			return previous_key.clone();
+		}
+
+		let factor = (time - self.times[next - 1]) / (self.times[next] - self.times[next - 1]);
+
+		previous_key.iter().zip(next_key.iter()).map(|(&a, &b)| a + (b - a) * factor).collect()
+	}
+
+	/// Overwrites the animated values with their values at the given time, returning false if the channel does not fit.
+	fn apply(&self, values: &mut [f32], time: f32) -> bool {
+		let sample = self.sample(time);
+
+		match self.component {
+			None if sample.len() == values.len() => values.copy_from_slice(&sample),
+			Some(component) if component < values.len() && sample.len() == 1 => values[component] = sample[0],
+			_ => return false
+		}
+
+		true
 	}
 }
 
+fn read_animations(ns: Option<&str>, root: &Element) -> Result<Animations, ConversionError> {
+	let mut channels = HashMap::new();
+
+	for animation in root.get_children("library_animations", ns).flat_map(|library| library.get_children("animation", ns)) {
+		read_animation(ns, animation, &mut channels)?;
+	}
+
+	let (start, end) = {
+		let mut times = channels.values().flat_map(|channels: &Vec<Channel>| channels.iter()).flat_map(|channel| channel.times.iter().cloned());
+
+		match times.next() {
+			Some(first) => times.fold((first, first), |(start, end), time| (start.min(time), end.max(time))),
+			None => (0.0, 0.0)
+		}
+	};
+
+	Ok(Animations { channels, start, end })
+}
+
+fn read_animation(ns: Option<&str>, animation: &Element, channels: &mut HashMap<String, Vec<Channel>>) -> Result<(), ConversionError> {
+	for channel in animation.get_children("channel", ns) {
+		let (source, target) = match (channel.get_attribute("source", None), channel.get_attribute("target", None)) {
+			(Some(source), Some(target)) => (trim_hash(source), target),
+			_ => {
+				eprintln!("warning[collada]: degenerate <channel> is missing a source or target");
+				continue;
+			}
+		};
+
+		// Targets are either a whole element such as "node/location", or one value of it such as "node/location.X" or
+		// "weights(2)".
+		let (element, component) = match target.find(['.', '(']) {
+			Some(position) => {
+				let suffix = &target[position..];
+
+				let component = match suffix {
+					".X" => Some(0),
+					".Y" => Some(1),
+					".Z" => Some(2),
+					".ANGLE" => Some(3),
+					_ if suffix.starts_with('(') && suffix.ends_with(')') => suffix[1..suffix.len() - 1].parse::<usize>().ok(),
+					_ => None
+				};
+
+				match component {
+					Some(component) => (&target[..position], Some(component)),
+					None => {
+						eprintln!("warning[collada]: unsupported animation target {} (component {}), ignoring it", target, suffix);
+						continue;
+					}
+				}
+			},
+			None => (target, None)
+		};
+
+		let sampler = match animation.get_children("sampler", ns).find(|sampler| sampler.get_attribute("id", None) == Some(source)) {
+			Some(sampler) => sampler,
+			None => {
+				eprintln!("warning[collada]: the sampler of the animation of {} does not exist, ignoring it", target);
+				continue;
+			}
+		};
+
+		let sampler_source = |semantic: &str| get_input(ns, sampler, semantic).and_then(|input| get_input_source(ns, animation, input));
+
+		let (input, output) = match (sampler_source("INPUT"), sampler_source("OUTPUT")) {
+			(Some(input), Some(output)) => (input, output),
+			_ => {
+				eprintln!("warning[collada]: animation of {} is missing its INPUT or OUTPUT source, ignoring it", target);
+				continue;
+			}
+		};
+
+		let times = read_source::<f32>(ns, input)?.into_iter().filter_map(|values| values.into_iter().next()).collect::<Vec<_>>();
+		let keys = read_source::<f32>(ns, output)?;
+
+		if times.len() != keys.len() {
+			return Err(ConversionError::collada(format!("COLLADA/library_animations//sampler[@id='{}']", source), format!("{} key times for {} key values", times.len(), keys.len())));
+		}
+
+		if times.is_empty() {
+			continue;
+		}
+
+		let interpolations = match sampler_source("INTERPOLATION") {
+			Some(interpolation) => read_source::<String>(ns, interpolation)?.into_iter().filter_map(|values| values.into_iter().next()).collect::<Vec<_>>(),
+			None => Vec::new()
+		};
+
+		let step = !interpolations.is_empty() && interpolations.iter().all(|interpolation| interpolation == "STEP");
+
+		if interpolations.iter().any(|interpolation| interpolation != "LINEAR" && interpolation != "STEP") {
+			eprintln!("warning[collada]: animation of {} uses curves, sampling it linearly between keys instead", target);
+		}
+
+		channels.entry(element.to_owned()).or_default().push(Channel { component, times, keys, step });
+	}
+
+	for child in animation.get_children("animation", ns) {
+		read_animation(ns, child, channels)?;
+	}
+
+	Ok(())
+}
+
+/// Reads a <source> through its accessor, returning the values of the named params of each element.
+///
+/// The accessor decides where each element starts in the array (offset and stride), how many elements there are (count),
+/// and which values of an element are used at all (params without a name are skipped).
+fn read_source<T>(ns: Option<&str>, source: &Element) -> Result<Vec<Vec<T>>, ConversionError> where T: FromStr {
+	let path = format!("COLLADA//source[@id='{}']", source.get_attribute("id", None).unwrap_or(""));
+
+	let accessor = source.get_child("technique_common", ns)
+		.and_then(|technique| technique.get_child("accessor", ns))
+		.ok_or_else(|| ConversionError::collada(path.clone(), "missing <technique_common><accessor>"))?;
+
+	let attribute = |name: &str, default: Option<usize>| -> Result<usize, ConversionError> {
+		match accessor.get_attribute(name, None) {
+			Some(value) => value.parse::<usize>().map_err(|_| ConversionError::collada(format!("{}/technique_common/accessor", path), format!("\"{}\" is not a number: {}", name, value))),
+			None => default.ok_or_else(|| ConversionError::collada(format!("{}/technique_common/accessor", path), format!("missing \"{}\" attribute", name)))
+		}
+	};
+
+	let count = attribute("count", None)?;
+	let offset = attribute("offset", Some(0))?;
+	let stride = attribute("stride", Some(1))?;
+
+	// Positions of the named params within each element.
+	let params = accessor.get_children("param", ns).collect::<Vec<_>>();
+
+	if params.len() > stride {
+		return Err(ConversionError::collada(format!("{}/technique_common/accessor", path), format!("{} params do not fit in a stride of {}", params.len(), stride)));
+	}
+
+	let used = if params.is_empty() {
+		(0..stride).collect::<Vec<_>>()
+	} else {
+		params.iter().enumerate().filter(|&(_, param)| param.get_attribute("name", None).is_some()).map(|(index, _)| index).collect()
+	};
+
+	// The array is usually inside the source, which is the only place this looks for it.
+	let array_id = accessor.get_attribute("source", None).map(trim_hash);
+
+	let array = source.children.iter()
+		.filter_map(|child| if let xml::Xml::ElementNode(element) = child { Some(element) } else { None })
+		.find(|element| element.name.ends_with("_array") && (array_id.is_none() || element.get_attribute("id", None) == array_id))
+		.ok_or_else(|| ConversionError::collada(path.clone(), format!("the array {} used by the accessor is not in the source", array_id.unwrap_or(""))))?;
+
+	let array_path = format!("{}/{}", path, array.name);
+
+	let values = array.children.iter()
+		.filter_map(|child| if let xml::Xml::CharacterNode(contents) = child { Some(contents) } else { None })
+		.flat_map(|contents| contents.split_whitespace())
+		.map(|value| value.parse::<T>().map_err(|_| ConversionError::collada(array_path.clone(), format!("cannot read the value {}", value))))
+		.collect::<Result<Vec<T>, ConversionError>>()?;
+
+	if let Some(array_count) = array.get_attribute("count", None) {
+		if array_count.parse::<usize>().ok() != Some(values.len()) {
+			return Err(ConversionError::collada(array_path, format!("\"count\" is {} but the array holds {} values", array_count, values.len())));
+		}
+	}
+
+	if count > 0 && offset + (count - 1) * stride + used.last().map(|&param| param + 1).unwrap_or(0) > values.len() {
+		return Err(ConversionError::collada(format!("{}/technique_common/accessor", path), format!("{} elements with a stride of {} from offset {} need more than the {} values in the array", count, stride, offset, values.len())));
+	}
+
+	let mut values = values.into_iter().map(Some).collect::<Vec<_>>();
+
+	Ok((0..count).map(|element| {
+		used.iter().map(|&param| values[offset + element * stride + param].take().unwrap()).collect()
+	}).collect())
+}
+
+/// Text content of an element.
+fn text(element: &Element) -> String {
+	element.children.iter()
+		.filter_map(|child| if let xml::Xml::CharacterNode(contents) = child { Some(contents as &str) } else { None })
+		.collect()
+}
+
+fn parse_floats(element: &Element) -> Option<Vec<f32>> {
+	element.children.iter()
+		.filter_map(|child| if let xml::Xml::CharacterNode(contents) = child { Some(contents) } else { None })
+		.flat_map(|contents| contents.split_whitespace())
+		.map(|value| value.parse::<f32>().ok())
+		.collect()
+}
+
+fn parse_indices(element: &Element) -> Option<Vec<usize>> {
+	element.children.iter()
+		.filter_map(|child| if let xml::Xml::CharacterNode(contents) = child { Some(contents) } else { None })
+		.flat_map(|contents| contents.split_whitespace())
+		.map(|value| value.parse::<usize>().ok())
+		.collect()
+}
+
+// Utilities for COLLADA (Mostly taken from private methods in piston_collada)
+
 fn trim_hash(name: &str) -> &str {
-	if name.starts_with('#') { &name[1..] } else { name }
+	name.strip_prefix('#').unwrap_or(name)
 }
 
 fn get_input<'a>(ns: Option<&'a str>, parent: &'a Element, semantic : &str) -> Option<&'a Element> {
@@ -287,7 +1412,7 @@ fn get_input_source<'a>(ns: Option<&'a str>, parent_element: &'a Element, input_
 	let source_id = input_element.get_attribute("source", None)?;
 
 	if let Some(element) = parent_element.children.iter()
-		.filter_map(|node| { if let &xml::Xml::ElementNode(ref e) = node { Some(e) } else { None } })
+		.filter_map(|node| { if let xml::Xml::ElementNode(e) = node { Some(e) } else { None } })
 		.find(|e| {
 			if let Some(id) = e.get_attribute("id", None) {
 				let id = "#".to_string() + id;
@@ -305,4 +1430,50 @@ fn get_input_source<'a>(ns: Option<&'a str>, parent_element: &'a Element, input_
 			}
 		}
 	None
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use cem::{v2, V2, Scene};
+	use cgmath::{Point2, Point3, Vector3};
+	use collada::document::ColladaDocument;
+	use collada_export;
+	use xml::Element;
+	use super::convert;
+
+	fn vertex(x: f32, y: f32) -> v2::Vertex {
+		v2::Vertex { position: Point3::new(x, y, 0.0), normal: Vector3::new(0.0, 0.0, 1.0), texture: Point2::new(x, y) }
+	}
+
+	/// A material with one triangle, which is the `index`th triangle of the model and uses its own three vertices.
+	fn material(name: &str, index: u32) -> v2::Material {
+		v2::Material {
+			name: name.to_owned(),
+			texture: 0,
+			triangles: vec![v2::TriangleSelection { offset: index, len: 1 }],
+			vertex_offset: index * 3,
+			vertex_count: 3,
+			texture_name: name.to_owned()
+		}
+	}
+
+	#[test]
+	fn keeps_every_material_through_export() {
+		let center = Point3::new(0.5, 0.5, 0.0);
+		let vertices = vec![vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0)];
+
+		let model = V2 {
+			center,
+			materials: vec![material("first", 0), material("second", 1)],
+			lod_levels: vec![vec![(0, 1, 2), (0, 1, 2)]],
+			tag_points: Vec::new(),
+			frames: vec![v2::Frame::from_vertices(vertices, Vec::new(), center)]
+		};
+
+		let document = collada_export::convert(Scene::root(model), 30.0, "tga").parse::<Element>().unwrap();
+		let scene = convert(ColladaDocument { root_element: document }, 30.0).unwrap();
+
+		let materials = scene.model.materials.iter().map(|material| (&material.texture_name as &str, material.triangles[0].len)).collect::<Vec<_>>();
+
+		assert_eq!(materials, vec![("first", 1), ("second", 1)]);
+	}
+}
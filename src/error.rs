@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ConversionError {
+	/// Reading the input or writing the output failed, or the CEM data itself is corrupt.
+	Io(io::Error),
+	/// An input or output format name given on the command line was not recognized.
+	UnrecognizedFormat(String),
+	/// No format was given, and it could not be worked out from the file contents or name.
+	UndetectedFormat(String),
+	/// The combination of command line options cannot be satisfied.
+	InvalidOptions(String),
+	/// There is no converter between these two formats.
+	UnsupportedConversion { from: String, to: String },
+	/// The SSMF header names a CEM version that cemconv cannot handle.
+	UnsupportedCemVersion { major: u16, minor: u16 },
+	/// The OBJ parser rejected the input.
+	MalformedObj { line: usize, message: String },
+	/// The COLLADA document is missing something, or contains something contradictory. The path
+	/// points at the offending element, for example `COLLADA/scene/instance_visual_scene`.
+	MalformedCollada { path: String, message: String },
+	/// The glTF document is not valid JSON, or is missing something it needs. The path points at the offending
+	/// property, for example `nodes[3]/mesh`.
+	MalformedGltf { path: String, message: String },
+	/// A frame was requested that the model does not have.
+	FrameOutOfRange { index: usize, frames: usize },
+	/// A frame read from a separate file does not share the topology of the first frame.
+	MismatchedFrame { index: usize, message: String }
+}
+
+impl ConversionError {
+	pub fn collada<P, M>(path: P, message: M) -> Self where P: Into<String>, M: Into<String> {
+		ConversionError::MalformedCollada { path: path.into(), message: message.into() }
+	}
+
+	pub fn gltf<P, M>(path: P, message: M) -> Self where P: Into<String>, M: Into<String> {
+		ConversionError::MalformedGltf { path: path.into(), message: message.into() }
+	}
+
+	/// Process exit code for this class of error, so that scripts can tell failures apart without parsing stderr.
+	pub fn exit_code(&self) -> i32 {
+		match *self {
+			ConversionError::Io(_) => 2,
+			ConversionError::UnrecognizedFormat(_) => 3,
+			ConversionError::UndetectedFormat(_) => 3,
+			ConversionError::InvalidOptions(_) => 3,
+			ConversionError::UnsupportedConversion { .. } => 4,
+			ConversionError::UnsupportedCemVersion { .. } => 5,
+			ConversionError::MalformedObj { .. } => 6,
+			ConversionError::MalformedCollada { .. } => 7,
+			ConversionError::FrameOutOfRange { .. } => 8,
+			ConversionError::MismatchedFrame { .. } => 9,
+			ConversionError::MalformedGltf { .. } => 10
+		}
+	}
+}
+
+impl fmt::Display for ConversionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ConversionError::Io(ref e) => write!(f, "{}", e),
+			ConversionError::UnrecognizedFormat(ref format) => write!(f, "Unrecognized format {:?}", format),
+			ConversionError::UndetectedFormat(ref message) => write!(f, "Could not determine the format: {}", message),
+			ConversionError::InvalidOptions(ref message) => write!(f, "Invalid options: {}", message),
+			ConversionError::UnsupportedConversion { ref from, ref to } => write!(f, "Conversion from {} to {} is not supported", from, to),
+			ConversionError::UnsupportedCemVersion { major, minor } => write!(f, "CEM version {}.{} is not supported", major, minor),
+			ConversionError::MalformedObj { line, ref message } => write!(f, "Error in OBJ file on line {}: {}", line, message),
+			ConversionError::MalformedCollada { ref path, ref message } => write!(f, "Error in COLLADA document at {}: {}", path, message),
+			ConversionError::MalformedGltf { ref path, ref message } => write!(f, "Error in glTF document at {}: {}", path, message),
+			ConversionError::FrameOutOfRange { index, frames } => write!(f, "Tried to extract frame index {} from a CEM file that only has {} frames", index, frames),
+			ConversionError::MismatchedFrame { index, ref message } => write!(f, "Frame {} does not match the topology of the first frame: {}", index, message)
+		}
+	}
+}
+
+impl Error for ConversionError {}
+
+impl From<io::Error> for ConversionError {
+	fn from(e: io::Error) -> Self {
+		ConversionError::Io(e)
+	}
+}
@@ -0,0 +1,462 @@
+//! glTF 2.0 export. Like the COLLADA export the JSON is written by hand, with all of the binary data in one buffer
+//! that is either embedded as a data URI (.gltf) or stored in the binary chunk (.glb).
+
+use cem::{v2, V2, Scene};
+use cgmath::{Point3, Vector3, Matrix4, Deg, InnerSpace};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use error::ConversionError;
+use lod::lod_name;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+/// The top level arrays of the document as they are built, along with the buffer that the accessors point into.
+#[derive(Default)]
+struct Document {
+	buffer: Vec<u8>,
+	buffer_views: Vec<String>,
+	accessors: Vec<String>,
+	images: Vec<String>,
+	/// Index of the texture of each texture name, so that materials sharing a texture share the image as well.
+	textures: HashMap<String, usize>,
+	materials: Vec<String>,
+	meshes: Vec<String>,
+	nodes: Vec<String>,
+	animation_samplers: Vec<String>,
+	animation_channels: Vec<String>
+}
+
+impl Document {
+	fn push_view(&mut self, offset: usize, target: Option<u32>) -> usize {
+		let target = target.map(|target| format!(r#","target":{}"#, target)).unwrap_or_default();
+
+		self.buffer_views.push(format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{}{}}}"#, offset, self.buffer.len() - offset, target));
+		self.buffer_views.len() - 1
+	}
+
+	/// Adds an accessor of float elements with the given type, such as `VEC3`. The bounds are always written, since
+	/// positions and animation inputs require them.
+	fn push_floats(&mut self, values: &[f32], kind: &str, target: Option<u32>) -> usize {
+		let components = match kind {
+			"VEC2" => 2,
+			"VEC3" => 3,
+			_ => 1
+		};
+
+		let offset = self.buffer.len();
+
+		for &value in values {
+			self.buffer.write_f32::<LittleEndian>(value).unwrap();
+		}
+
+		let view = self.push_view(offset, target);
+
+		let mut min = vec![f32::INFINITY; components];
+		let mut max = vec![f32::NEG_INFINITY; components];
+
+		for element in values.chunks(components) {
+			for (component, &value) in element.iter().enumerate() {
+				min[component] = min[component].min(value);
+				max[component] = max[component].max(value);
+			}
+		}
+
+		// Empty accessors have no bounds.
+		let bounds = if values.is_empty() { String::new() } else { format!(r#","min":{},"max":{}"#, array(&min), array(&max)) };
+
+		self.accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"{}"{}}}"#, view, FLOAT, values.len() / components, kind, bounds));
+		self.accessors.len() - 1
+	}
+
+	fn push_indices(&mut self, indices: &[u32]) -> usize {
+		let offset = self.buffer.len();
+
+		for &index in indices {
+			self.buffer.write_u32::<LittleEndian>(index).unwrap();
+		}
+
+		let view = self.push_view(offset, Some(ELEMENT_ARRAY_BUFFER));
+
+		self.accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"SCALAR"}}"#, view, UNSIGNED_INT, indices.len()));
+		self.accessors.len() - 1
+	}
+
+	/// Adds a linearly interpolated animation of a node property with a key for each frame, at `frame_rate` frames per
+	/// second.
+	fn push_animation(&mut self, nodes: &[usize], path: &str, values: &[f32], kind: &str, frames: usize, frame_rate: f32) {
+		let times = (0..frames).map(|frame| frame as f32 / frame_rate).collect::<Vec<_>>();
+
+		let input = self.push_floats(&times, "SCALAR", None);
+		let output = self.push_floats(values, kind, None);
+
+		self.animation_samplers.push(format!(r#"{{"input":{},"output":{},"interpolation":"LINEAR"}}"#, input, output));
+		let sampler = self.animation_samplers.len() - 1;
+
+		for &node in nodes {
+			self.animation_channels.push(format!(r#"{{"sampler":{},"target":{{"node":{},"path":"{}"}}}}"#, sampler, node, path));
+		}
+	}
+
+	fn texture(&mut self, texture_name: &str, texture_extension: &str) -> usize {
+		if let Some(&index) = self.textures.get(texture_name) {
+			return index;
+		}
+
+		self.images.push(format!(r#"{{"uri":"{}"}}"#, escape(&format!("{}.{}", texture_name, texture_extension))));
+
+		let index = self.textures.len();
+		self.textures.insert(texture_name.to_owned(), index);
+
+		index
+	}
+
+	/// Writes the JSON of the document, with the buffer at `buffer_uri` or in the binary chunk if there is none.
+	fn json(&self, roots: &[usize], buffer_uri: Option<&str>) -> String {
+		let mut string = String::new();
+
+		string.push_str(r#"{"asset":{"version":"2.0","generator":"cemconv"},"scene":0,"#);
+		write!(string, r#""scenes":[{{"nodes":{}}}],"#, array(roots)).unwrap();
+		write!(string, r#""nodes":[{}],"#, self.nodes.join(",")).unwrap();
+
+		if !self.meshes.is_empty() {
+			write!(string, r#""meshes":[{}],"#, self.meshes.join(",")).unwrap();
+		}
+
+		if !self.materials.is_empty() {
+			write!(string, r#""materials":[{}],"#, self.materials.join(",")).unwrap();
+		}
+
+		if !self.images.is_empty() {
+			let textures = (0..self.images.len()).map(|index| format!(r#"{{"source":{}}}"#, index)).collect::<Vec<_>>();
+
+			write!(string, r#""textures":[{}],"images":[{}],"#, textures.join(","), self.images.join(",")).unwrap();
+		}
+
+		if !self.animation_channels.is_empty() {
+			write!(string, r#""animations":[{{"name":"frames","samplers":[{}],"channels":[{}]}}],"#, self.animation_samplers.join(","), self.animation_channels.join(",")).unwrap();
+		}
+
+		if !self.accessors.is_empty() {
+			write!(string, r#""accessors":[{}],"bufferViews":[{}],"#, self.accessors.join(","), self.buffer_views.join(",")).unwrap();
+		}
+
+		// Buffers need at least one byte, so scenes without any geometry have none.
+		match buffer_uri {
+			_ if self.buffer.is_empty() => (),
+			Some(uri) => write!(string, r#""buffers":[{{"byteLength":{},"uri":"{}"}}],"#, self.buffer.len(), uri).unwrap(),
+			None => write!(string, r#""buffers":[{{"byteLength":{}}}],"#, self.buffer.len()).unwrap()
+		}
+
+		// Every member above ends with a comma.
+		string.pop();
+		string.push('}');
+
+		string
+	}
+}
+
+/// Adds the nodes of a model and its children, returning the nodes to list under the parent: the model itself, followed
+/// by its lower levels of detail, see the lod module for the naming.
+fn write_node(scene: &Scene<V2>, frame_rate: f32, texture_extension: &str, document: &mut Document) -> Vec<usize> {
+	let model = &scene.model;
+	let name = if scene.name.is_empty() { "Scene_Root" } else { &scene.name as &str };
+
+	let index = document.nodes.len();
+	document.nodes.push(String::new());
+
+	let meshes = write_meshes(name, model, texture_extension, document);
+
+	let transform = Matrix4::from_angle_x(Deg(-90.0));
+	let mut children = Vec::new();
+
+	for (tag_index, tag_name) in model.tag_points.iter().enumerate() {
+		let positions = model.frames.iter()
+			.filter_map(|frame| frame.tag_points.get(tag_index))
+			.map(|position| Point3::from_homogeneous(transform * position.to_homogeneous()))
+			.collect::<Vec<_>>();
+
+		let position = positions.first().cloned().unwrap_or(Point3::new(0.0, 0.0, 0.0));
+
+		let node = document.nodes.len();
+
+		document.nodes.push(format!(r#"{{"name":"{}","translation":[{},{},{}]}}"#, escape(tag_name), position.x, position.y, position.z));
+		children.push(node);
+
+		// Only tag points that move between frames are animated.
+		if positions.len() > 1 && !positions.windows(2).all(|pair| pair[0] == pair[1]) {
+			let values = positions.iter().flat_map(|position| vec![position.x, position.y, position.z]).collect::<Vec<f32>>();
+
+			document.push_animation(&[node], "translation", &values, "VEC3", positions.len(), frame_rate);
+		}
+	}
+
+	for child in &scene.children {
+		children.extend(write_node(child, frame_rate, texture_extension, document));
+	}
+
+	let mut nodes = vec![index];
+
+	for _ in 1..meshes.len() {
+		document.nodes.push(String::new());
+		nodes.push(document.nodes.len() - 1);
+	}
+
+	for (lod, &node) in nodes.iter().enumerate() {
+		let mut json = format!(r#"{{"name":"{}""#, escape(&lod_name(name, lod)));
+
+		if let Some(&Some(mesh)) = meshes.get(lod) {
+			write!(json, r#","mesh":{}"#, mesh).unwrap();
+		}
+
+		if lod == 0 && !children.is_empty() {
+			write!(json, r#","children":{}"#, array(&children)).unwrap();
+		}
+
+		json.push('}');
+		document.nodes[node] = json;
+	}
+
+	let animated = nodes.iter().zip(meshes.iter()).filter(|&(_, mesh)| mesh.is_some()).map(|(&node, _)| node).collect::<Vec<_>>();
+
+	if model.frames.len() > 1 && !animated.is_empty() {
+		// Each frame after the first is a morph target, fully shown at its own time step and blended in between.
+		let targets = model.frames.len() - 1;
+		let weights = (0..model.frames.len())
+			.flat_map(|frame| (0..targets).map(move |target| if frame == target + 1 { 1.0 } else { 0.0 }))
+			.collect::<Vec<f32>>();
+
+		document.push_animation(&animated, "weights", &weights, "SCALAR", model.frames.len(), frame_rate);
+	}
+
+	nodes
+}
+
+/// Adds a mesh for each level of detail of a model, all sharing the vertex data of the model. Levels of detail without
+/// any triangles have no mesh, since glTF meshes need at least one primitive, and neither do models without vertices,
+/// since accessors need at least one element.
+fn write_meshes(name: &str, model: &V2, texture_extension: &str, document: &mut Document) -> Vec<Option<usize>> {
+	let frame = match model.frames.first() {
+		Some(frame) if !frame.vertices.is_empty() => frame,
+		_ => return Vec::new()
+	};
+
+	let transform = Matrix4::from_angle_x(Deg(-90.0));
+
+	let vertices = |frame: &v2::Frame| {
+		let mut positions = Vec::with_capacity(frame.vertices.len() * 3);
+		let mut normals = Vec::with_capacity(frame.vertices.len() * 3);
+
+		for vertex in &frame.vertices {
+			let position = Point3::from_homogeneous(transform * vertex.position.to_homogeneous());
+			// Zero normals can not be normalized, and glTF requires unit normals, so they point up instead.
+			let normal = if vertex.normal.magnitude2() > 0.0 { vertex.normal.normalize() } else { Vector3::unit_z() };
+			let normal = (transform * normal.extend(0.0)).truncate();
+
+			positions.extend_from_slice(&[position.x, position.y, position.z]);
+			normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+		}
+
+		(positions, normals)
+	};
+
+	let (positions, normals) = vertices(frame);
+	let texture_coordinates = frame.vertices.iter().flat_map(|vertex| vec![vertex.texture.x, vertex.texture.y]).collect::<Vec<f32>>();
+
+	let position = document.push_floats(&positions, "VEC3", Some(ARRAY_BUFFER));
+	let normal = document.push_floats(&normals, "VEC3", Some(ARRAY_BUFFER));
+	let texture = document.push_floats(&texture_coordinates, "VEC2", Some(ARRAY_BUFFER));
+
+	// Morph targets hold the difference from the first frame.
+	let mut targets = Vec::with_capacity(model.frames.len() - 1);
+
+	for frame in &model.frames[1..] {
+		let (frame_positions, frame_normals) = vertices(frame);
+
+		let position_offsets = frame_positions.iter().zip(positions.iter()).map(|(frame, base)| frame - base).collect::<Vec<_>>();
+		let normal_offsets = frame_normals.iter().zip(normals.iter()).map(|(frame, base)| frame - base).collect::<Vec<_>>();
+
+		let position = document.push_floats(&position_offsets, "VEC3", Some(ARRAY_BUFFER));
+		let normal = document.push_floats(&normal_offsets, "VEC3", Some(ARRAY_BUFFER));
+
+		targets.push(format!(r#"{{"POSITION":{},"NORMAL":{}}}"#, position, normal));
+	}
+
+	let targets = if targets.is_empty() {
+		String::new()
+	} else {
+		format!(r#","targets":[{}]"#, targets.join(","))
+	};
+
+	let mut materials = Vec::with_capacity(model.materials.len());
+
+	for (index, material) in model.materials.iter().enumerate() {
+		let material_name = if material.name.is_empty() { format!("{}_material{}", name, index) } else { material.name.clone() };
+
+		let color = if material.texture_name.is_empty() {
+			r#""baseColorFactor":[0.8,0.8,0.8,1]"#.to_owned()
+		} else {
+			format!(r#""baseColorTexture":{{"index":{}}}"#, document.texture(&material.texture_name, texture_extension))
+		};
+
+		document.materials.push(format!(r#"{{"name":"{}","pbrMetallicRoughness":{{{},"metallicFactor":0}}}}"#, escape(&material_name), color));
+		materials.push(document.materials.len() - 1);
+	}
+
+	let mut meshes = Vec::with_capacity(model.lod_levels.len());
+
+	for (lod, triangle_data) in model.lod_levels.iter().enumerate() {
+		let mut primitives = Vec::new();
+
+		for (material, gltf_material) in model.materials.iter().zip(materials.iter()) {
+			let selection = match material.triangles.get(lod) {
+				Some(&selection) if selection.len > 0 => selection,
+				_ => continue
+			};
+
+			let mut indices = Vec::with_capacity(selection.len as usize * 3);
+
+			for triangle in &triangle_data[selection.offset as usize..(selection.offset + selection.len) as usize] {
+				indices.push(material.vertex_offset + triangle.0);
+				indices.push(material.vertex_offset + triangle.1);
+				indices.push(material.vertex_offset + triangle.2);
+			}
+
+			let indices = document.push_indices(&indices);
+
+			primitives.push(format!(r#"{{"attributes":{{"POSITION":{},"NORMAL":{},"TEXCOORD_0":{}}},"indices":{},"material":{}{}}}"#, position, normal, texture, indices, gltf_material, targets));
+		}
+
+		if primitives.is_empty() {
+			meshes.push(None);
+			continue;
+		}
+
+		let weights = if model.frames.len() > 1 {
+			format!(r#","weights":{}"#, array(&vec![0.0; model.frames.len() - 1]))
+		} else {
+			String::new()
+		};
+
+		document.meshes.push(format!(r#"{{"name":"{}","primitives":[{}]{}}}"#, escape(&lod_name(name, lod)), primitives.join(","), weights));
+		meshes.push(Some(document.meshes.len() - 1));
+	}
+
+	meshes
+}
+
+/// Converts a scene to glTF, with the frames of animated models played back at `frame_rate` frames per second.
+/// Textures are referenced as `<texture_name>.<texture_extension>`. With `binary`, the result is a .glb file.
+pub fn convert(cem: &Scene<V2>, frame_rate: f32, texture_extension: &str, binary: bool) -> Result<Vec<u8>, ConversionError> {
+	check_finite(cem)?;
+
+	match &texture_extension.to_lowercase() as &str {
+		"png" | "jpg" | "jpeg" => (),
+		_ => eprintln!("warning[gltf]: glTF viewers only load png and jpeg textures, not {}, see --texture-ext", texture_extension)
+	}
+
+	let mut document = Document::default();
+
+	let roots = write_node(cem, frame_rate, texture_extension, &mut document);
+
+	if binary {
+		let mut json = document.json(&roots, None).into_bytes();
+
+		// Both chunks have to be padded to 4 bytes, the JSON with spaces.
+		while json.len() % 4 != 0 {
+			json.push(b' ');
+		}
+
+		let mut buffer = document.buffer;
+
+		while buffer.len() % 4 != 0 {
+			buffer.push(0);
+		}
+
+		// The binary chunk is left out along with the buffer when there is no geometry.
+		let binary_length = if buffer.is_empty() { 0 } else { 8 + buffer.len() };
+
+		let mut glb = Vec::with_capacity(12 + 8 + json.len() + binary_length);
+
+		glb.extend_from_slice(b"glTF");
+		glb.write_u32::<LittleEndian>(2).unwrap();
+		glb.write_u32::<LittleEndian>((12 + 8 + json.len() + binary_length) as u32).unwrap();
+
+		glb.write_u32::<LittleEndian>(json.len() as u32).unwrap();
+		glb.extend_from_slice(b"JSON");
+		glb.extend_from_slice(&json);
+
+		if !buffer.is_empty() {
+			glb.write_u32::<LittleEndian>(buffer.len() as u32).unwrap();
+			glb.extend_from_slice(b"BIN\0");
+			glb.extend_from_slice(&buffer);
+		}
+
+		Ok(glb)
+	} else {
+		let uri = format!("data:application/octet-stream;base64,{}", base64(&document.buffer));
+
+		Ok(document.json(&roots, Some(&uri)).into_bytes())
+	}
+}
+
+/// Checks that every vertex and tag point of the scene is finite, as the bounds and translations in the JSON can not
+/// hold infinities or NaN.
+fn check_finite(scene: &Scene<V2>) -> Result<(), ConversionError> {
+	for (index, frame) in scene.model.frames.iter().enumerate() {
+		let vertices = frame.vertices.iter().all(|vertex| {
+			[vertex.position.x, vertex.position.y, vertex.position.z, vertex.normal.x, vertex.normal.y, vertex.normal.z, vertex.texture.x, vertex.texture.y].iter().all(|value| value.is_finite())
+		});
+
+		let tag_points = frame.tag_points.iter().all(|position| position.x.is_finite() && position.y.is_finite() && position.z.is_finite());
+
+		if !vertices || !tag_points {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame {} of {} has vertices or tag points that are not finite", index, scene.name)).into());
+		}
+	}
+
+	scene.children.iter().try_for_each(check_finite)
+}
+
+fn array<T>(values: &[T]) -> String where T: ::std::fmt::Display {
+	format!("[{}]", values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(","))
+}
+
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+			c => escaped.push(c)
+		}
+	}
+
+	escaped
+}
+
+fn base64(data: &[u8]) -> String {
+	const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+	let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+	for chunk in data.chunks(3) {
+		let bits = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+
+		for index in 0..4 {
+			if index <= chunk.len() {
+				encoded.push(ALPHABET[(bits >> (18 - index * 6) & 63) as usize] as char);
+			} else {
+				encoded.push('=');
+			}
+		}
+	}
+
+	encoded
+}
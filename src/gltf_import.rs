@@ -0,0 +1,1013 @@
+//! glTF 2.0 import, from .gltf documents with their buffers embedded or in files next to them, or from .glb files.
+
+use cem::{v2, V2, Scene, collider};
+use cgmath::{Point2, Point3, Vector3, Matrix4, Quaternion, Deg, SquareMatrix, InnerSpace, EuclideanSpace};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str;
+use error::ConversionError;
+use json::{self, Value};
+use lod::split_lod;
+use mesh_builder::{MaterialBuilder, MAX_FRAMES, layout, merge_vertices, normal_matrix, texture_name};
+
+pub fn convert(data: &[u8], input_path: Option<&str>, frame_rate: f32) -> Result<Scene<V2>, ConversionError> {
+	let (root, binary) = read_container(data)?;
+
+	let document = Document {
+		root: &root,
+		buffers: read_buffers(&root, binary, input_path)?
+	};
+
+	let nodes = elements(root.get("nodes"));
+
+	let roots = match root.get("scene").and_then(Value::as_usize).or(if root.get("scenes").is_some() { Some(0) } else { None }) {
+		Some(scene) => indices(document.get("scenes", scene)?.get("nodes")),
+		// Without any scenes, every node that is not the child of another is a root.
+		None => (0..nodes.len()).filter(|&index| !nodes.iter().any(|node| indices(node.get("children")).contains(&index))).collect()
+	};
+
+	let animations = read_animations(&document)?;
+
+	let mut graph = SceneGraph {
+		nodes,
+		path: Vec::new(),
+		instances: Vec::new(),
+		tag_points: Vec::new()
+	};
+
+	for node in roots {
+		graph.walk(node, None)?;
+	}
+
+	let SceneGraph { instances, tag_points, .. } = graph;
+
+	// Nodes named with a _lod<n> suffix hold the lower levels of detail of the node with the plain name.
+	// Each model also remembers the model it is nested under, which always comes before it.
+	let mut models: Vec<(String, Option<usize>, Vec<Option<Instance>>)> = Vec::new();
+
+	for instance in instances {
+		let (base, lod) = split_lod(&instance.node_name);
+
+		let parent = instance.parent.as_ref().and_then(|parent| models.iter().position(|(name, _, _)| name == parent));
+
+		let index = match models.iter().position(|(name, _, _)| name == base) {
+			Some(index) => index,
+			None => {
+				models.push((base.to_owned(), parent, Vec::new()));
+				models.len() - 1
+			}
+		};
+
+		let lods = &mut models[index].2;
+
+		while lods.len() <= lod {
+			lods.push(None);
+		}
+
+		if lods[lod].is_some() {
+			eprintln!("warning[gltf]: there is more than one node named {}, ignoring the mesh of node {}", instance.node_name, instance.node);
+		} else {
+			lods[lod] = Some(instance);
+		}
+	}
+
+	if models.is_empty() {
+		return Err(ConversionError::gltf("nodes", "no node in the scene has a mesh"));
+	}
+
+	// Tag points belong to the model they are nested under, or the root model if they are not nested under any.
+	let mut model_tag_points = vec![Vec::new(); models.len()];
+
+	for tag_point in &tag_points {
+		let owner = tag_point.parent.as_ref().and_then(|parent| models.iter().position(|(name, _, _)| name == parent)).unwrap_or(0);
+
+		model_tag_points[owner].push(tag_point);
+	}
+
+	let model_lods = models.iter().map(|(name, _, lods)| lods.iter().enumerate().filter_map(|(lod, instance)| {
+		if instance.is_none() {
+			eprintln!("warning[gltf]: {} has no mesh for level of detail {}, skipping it", name, lod);
+		}
+
+		instance.as_ref()
+	}).collect::<Vec<&Instance>>()).collect::<Vec<_>>();
+
+	// Every model of the scene has the same frames. If anything is animated, every model is sampled over the whole
+	// animation. Otherwise the base mesh is the first frame and each morph target another, and models with fewer morph
+	// targets than others reuse their base mesh for the missing frames.
+	let animated = model_lods.iter().zip(model_tag_points.iter())
+		.any(|(lods, tag_points)| animates(&animations, lods, tag_points));
+
+	let frame_count = if animated {
+		animations.frame_count(frame_rate)?
+	} else {
+		model_lods.iter().flat_map(|lods| lods.iter()).map(|instance| target_count(&document, instance.mesh) + 1).max().unwrap_or(1)
+	};
+
+	// The first node with a mesh becomes the root model, and the others become submodels of the model they are nested
+	// under, or of the root model if they are not nested under any.
+	let mut scenes = Vec::with_capacity(models.len());
+
+	for (((name, _, _), lods), tag_points) in models.iter().zip(model_lods.iter()).zip(model_tag_points.iter()) {
+		if !animated {
+			for (lod, instance) in lods.iter().enumerate() {
+				let count = target_count(&document, instance.mesh) + 1;
+
+				if count != frame_count {
+					eprintln!("warning[gltf]: {} has {} frames at level of detail {} instead of {}, reusing its base mesh for the missing ones", name, count, lod, frame_count);
+				}
+			}
+		}
+
+		scenes.push(Some(Scene {
+			name: name.clone(),
+			model: build_model(&document, lods, tag_points, &animations, frame_rate, animated, frame_count)?,
+			children: Vec::new()
+		}));
+	}
+
+	// Children always come after their parents, so attaching them back to front builds the tree bottom up.
+	for index in (1..scenes.len()).rev() {
+		let scene = scenes[index].take().unwrap();
+		let parent = models[index].1.unwrap_or(0);
+
+		scenes[parent].as_mut().unwrap().children.insert(0, scene);
+	}
+
+	Ok(scenes[0].take().unwrap())
+}
+
+/// Splits a .glb file into its JSON and binary chunks, or takes a .gltf document as is.
+fn read_container(data: &[u8]) -> Result<(Value, Option<&[u8]>), ConversionError> {
+	let (text, binary) = if data.starts_with(b"glTF") {
+		if data.len() < 12 {
+			return Err(ConversionError::gltf("glTF", "truncated .glb header"));
+		}
+
+		let version = LittleEndian::read_u32(&data[4..8]);
+
+		if version != 2 {
+			return Err(ConversionError::gltf("glTF", format!("unsupported .glb version {}", version)));
+		}
+
+		let length = (LittleEndian::read_u32(&data[8..12]) as usize).min(data.len());
+
+		let mut json = None;
+		let mut binary = None;
+		let mut position = 12;
+
+		while position + 8 <= length {
+			let chunk_length = LittleEndian::read_u32(&data[position..position + 4]) as usize;
+			let kind = &data[position + 4..position + 8];
+
+			let chunk = (position + 8).checked_add(chunk_length).and_then(|end| data.get(position + 8..end))
+				.ok_or_else(|| ConversionError::gltf("glTF", format!("the chunk at byte {} runs past the end of the file", position)))?;
+
+			// Unknown chunks are skipped, as the specification asks.
+			if kind == b"JSON" {
+				json = Some(chunk);
+			} else if kind == b"BIN\0" {
+				binary = Some(chunk);
+			}
+
+			position += 8 + chunk_length;
+		}
+
+		(json.ok_or_else(|| ConversionError::gltf("glTF", "missing the JSON chunk"))?, binary)
+	} else {
+		(data, None)
+	};
+
+	let text = str::from_utf8(text).map_err(|e| ConversionError::gltf("glTF", format!("the JSON is not UTF-8 ({})", e)))?;
+	let root = json::parse(text.trim_start_matches('\u{feff}')).map_err(|message| ConversionError::gltf("glTF", message))?;
+
+	Ok((root, binary))
+}
+
+/// Loads every buffer of the document, from the binary chunk of a .glb file, a data URI, or a file next to the document.
+fn read_buffers(root: &Value, binary: Option<&[u8]>, input_path: Option<&str>) -> Result<Vec<Vec<u8>>, ConversionError> {
+	let mut buffers = Vec::new();
+
+	for (index, buffer) in elements(root.get("buffers")).iter().enumerate() {
+		let path = format!("buffers[{}]", index);
+
+		let length = buffer.get("byteLength").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path.clone(), "missing \"byteLength\""))?;
+
+		let data = match buffer.get("uri").and_then(Value::as_str) {
+			// The binary chunk of a .glb file is the first buffer, which has no uri.
+			None => match binary {
+				Some(binary) if index == 0 => binary.to_vec(),
+				_ => return Err(ConversionError::gltf(path, "missing \"uri\", and there is no binary chunk to use instead"))
+			},
+			Some(uri) if uri.starts_with("data:") => {
+				let encoded = uri.find(";base64,").map(|start| &uri[start + 8..]).ok_or_else(|| ConversionError::gltf(path.clone(), "only base64 data URIs are supported"))?;
+
+				base64(encoded).ok_or_else(|| ConversionError::gltf(path.clone(), "invalid base64 in the data URI"))?
+			},
+			Some(uri) => {
+				// Buffer files are relative to the document.
+				let file = match input_path.and_then(|path| Path::new(path).parent()) {
+					Some(parent) => parent.join(decode_uri(uri)),
+					None => Path::new(&decode_uri(uri)).to_path_buf()
+				};
+
+				let mut data = Vec::new();
+
+				File::open(&file).and_then(|mut opened| opened.read_to_end(&mut data))
+					.map_err(|e| io::Error::new(e.kind(), format!("failed to read the glTF buffer at {} ({})", file.display(), e)))?;
+
+				data
+			}
+		};
+
+		if data.len() < length {
+			return Err(ConversionError::gltf(path, format!("holds {} bytes instead of {}", data.len(), length)));
+		}
+
+		buffers.push(data);
+	}
+
+	Ok(buffers)
+}
+
+/// Most elements an accessor without a buffer view can have.
+const MAX_ELEMENTS: usize = 1 << 22;
+
+/// The JSON of a glTF document, along with the contents of its buffers.
+struct Document<'a> {
+	root: &'a Value,
+	buffers: Vec<Vec<u8>>
+}
+
+impl<'a> Document<'a> {
+	/// An element of one of the top level arrays, such as `meshes`.
+	fn get(&self, collection: &str, index: usize) -> Result<&'a Value, ConversionError> {
+		elements(self.root.get(collection)).get(index).ok_or_else(|| ConversionError::gltf(format!("{}[{}]", collection, index), "does not exist"))
+	}
+
+	/// Reads every element of an accessor, with one value for each component. Normalized integers are mapped to [0, 1],
+	/// or [-1, 1] if they are signed.
+	fn accessor(&self, index: usize) -> Result<Vec<Vec<f64>>, ConversionError> {
+		let path = format!("accessors[{}]", index);
+		let accessor = self.get("accessors", index)?;
+
+		let count = accessor.get("count").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path.clone(), "missing \"count\""))?;
+
+		let components = match accessor.get("type").and_then(Value::as_str).unwrap_or("") {
+			"SCALAR" => 1,
+			"VEC2" => 2,
+			"VEC3" => 3,
+			"VEC4" | "MAT2" => 4,
+			"MAT3" => 9,
+			"MAT4" => 16,
+			kind => return Err(ConversionError::gltf(path, format!("unknown accessor type {:?}", kind)))
+		};
+
+		let component_type = accessor.get("componentType").and_then(Value::as_usize).unwrap_or(0);
+
+		let (size, read): (usize, fn(&[u8]) -> f64) = match component_type {
+			5120 => (1, |bytes: &[u8]| bytes[0] as i8 as f64),
+			5121 => (1, |bytes: &[u8]| bytes[0] as f64),
+			5122 => (2, |bytes: &[u8]| LittleEndian::read_i16(bytes) as f64),
+			5123 => (2, |bytes: &[u8]| LittleEndian::read_u16(bytes) as f64),
+			5125 => (4, |bytes: &[u8]| LittleEndian::read_u32(bytes) as f64),
+			5126 => (4, |bytes: &[u8]| LittleEndian::read_f32(bytes) as f64),
+			_ => return Err(ConversionError::gltf(path, format!("unknown component type {}", component_type)))
+		};
+
+		let normalized = accessor.get("normalized") == Some(&Value::Bool(true));
+
+		let scale = match component_type {
+			5120 if normalized => 127.0,
+			5121 if normalized => 255.0,
+			5122 if normalized => 32767.0,
+			5123 if normalized => 65535.0,
+			_ => 1.0
+		};
+
+		let element_size = components * size;
+
+		let decode = |bytes: &[u8]| bytes.chunks(size).map(|component| {
+			let value = read(component) / scale;
+
+			if normalized { value.max(-1.0) } else { value }
+		}).collect::<Vec<f64>>();
+
+		let mut elements = match accessor.get("bufferView").and_then(Value::as_usize) {
+			Some(view_index) => {
+				let (data, stride) = self.view(view_index)?;
+				let stride = stride.unwrap_or(element_size);
+
+				// Elements can not overlap, which also keeps a zero stride from reading any count of elements from one spot.
+				if stride < element_size {
+					return Err(ConversionError::gltf(path, format!("the stride of {} bytes is smaller than the elements of {} bytes", stride, element_size)));
+				}
+
+				let offset = accessor.get("byteOffset").and_then(Value::as_usize).unwrap_or(0);
+
+				// The count comes straight from the file, so it is checked against the buffer view before allocating anything.
+				let end = match count {
+					0 => Some(0),
+					_ => (count - 1).checked_mul(stride).and_then(|last| last.checked_add(offset)).and_then(|last| last.checked_add(element_size))
+				};
+
+				if end.is_none_or(|end| end > data.len()) {
+					return Err(ConversionError::gltf(path, format!("{} elements with a stride of {} run past the end of the buffer view of {} bytes", count, stride, data.len())));
+				}
+
+				(0..count).map(|element| decode(&data[offset + element * stride..][..element_size])).collect::<Vec<_>>()
+			},
+			// Accessors without a buffer view are all zeros, with nothing in the file to check their count against.
+			None if count > MAX_ELEMENTS => return Err(ConversionError::gltf(path, format!("{} elements without a buffer view are more than the limit of {}", count, MAX_ELEMENTS))),
+			None => vec![vec![0.0; components]; count]
+		};
+
+		// Sparse accessors replace some of the elements, which is how morph targets that only move a few vertices are
+		// usually stored.
+		if let Some(sparse) = accessor.get("sparse") {
+			let path = format!("{}/sparse", path);
+
+			let sparse_count = sparse.get("count").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path.clone(), "missing \"count\""))?;
+
+			let indices_path = format!("{}/indices", path);
+			let indices = sparse.get("indices");
+
+			let (index_size, read_index): (usize, fn(&[u8]) -> usize) = match indices.and_then(|indices| indices.get("componentType")).and_then(Value::as_usize) {
+				Some(5121) => (1, |bytes: &[u8]| bytes[0] as usize),
+				Some(5123) => (2, |bytes: &[u8]| LittleEndian::read_u16(bytes) as usize),
+				Some(5125) => (4, |bytes: &[u8]| LittleEndian::read_u32(bytes) as usize),
+				_ => return Err(ConversionError::gltf(indices_path, "missing or invalid \"componentType\""))
+			};
+
+			let index_data = self.sparse_data(indices, sparse_count, index_size, &indices_path)?;
+			let value_data = self.sparse_data(sparse.get("values"), sparse_count, element_size, &format!("{}/values", path))?;
+
+			for (index, value) in index_data.chunks(index_size).zip(value_data.chunks(element_size)) {
+				let index = read_index(index);
+
+				let element = elements.get_mut(index)
+					.ok_or_else(|| ConversionError::gltf(indices_path.clone(), format!("index {} is out of range for {} elements", index, count)))?;
+
+				*element = decode(value);
+			}
+		}
+
+		Ok(elements)
+	}
+
+	/// The tightly packed indices or values of a sparse accessor.
+	fn sparse_data(&self, part: Option<&Value>, count: usize, size: usize, path: &str) -> Result<&[u8], ConversionError> {
+		let part = part.ok_or_else(|| ConversionError::gltf(path, "missing"))?;
+		let view = part.get("bufferView").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path, "missing \"bufferView\""))?;
+
+		let (data, _) = self.view(view)?;
+		let offset = part.get("byteOffset").and_then(Value::as_usize).unwrap_or(0);
+
+		count.checked_mul(size).and_then(|length| length.checked_add(offset)).and_then(|end| data.get(offset..end))
+			.ok_or_else(|| ConversionError::gltf(path, format!("{} elements run past the end of the buffer view of {} bytes", count, data.len())))
+	}
+
+	/// The bytes of a buffer view, along with its stride if it has one.
+	fn view(&self, index: usize) -> Result<(&[u8], Option<usize>), ConversionError> {
+		let path = format!("bufferViews[{}]", index);
+		let view = self.get("bufferViews", index)?;
+
+		let buffer = view.get("buffer").and_then(Value::as_usize).and_then(|buffer| self.buffers.get(buffer))
+			.ok_or_else(|| ConversionError::gltf(path.clone(), "missing or invalid \"buffer\""))?;
+
+		let offset = view.get("byteOffset").and_then(Value::as_usize).unwrap_or(0);
+		let length = view.get("byteLength").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path.clone(), "missing \"byteLength\""))?;
+
+		let data = offset.checked_add(length).and_then(|end| buffer.get(offset..end))
+			.ok_or_else(|| ConversionError::gltf(path, format!("runs past the end of its buffer of {} bytes", buffer.len())))?;
+
+		Ok((data, view.get("byteStride").and_then(Value::as_usize)))
+	}
+
+	/// Reads an accessor of three component vectors, such as positions or normals.
+	fn vectors(&self, index: usize) -> Result<Vec<Vector3<f32>>, ConversionError> {
+		let elements = self.accessor(index)?;
+
+		if elements.iter().any(|element| element.len() != 3) {
+			return Err(ConversionError::gltf(format!("accessors[{}]", index), "expected VEC3 elements"));
+		}
+
+		Ok(elements.iter().map(|element| Vector3::new(element[0] as f32, element[1] as f32, element[2] as f32)).collect())
+	}
+
+	/// Reads an accessor of texture coordinates.
+	fn points(&self, index: usize) -> Result<Vec<Point2<f32>>, ConversionError> {
+		let elements = self.accessor(index)?;
+
+		if elements.iter().any(|element| element.len() != 2) {
+			return Err(ConversionError::gltf(format!("accessors[{}]", index), "expected VEC2 elements"));
+		}
+
+		Ok(elements.iter().map(|element| Point2::new(element[0] as f32, element[1] as f32)).collect())
+	}
+
+	/// Name and texture name of a material, with the texture name taken from the file name of the base color texture.
+	fn material(&self, index: Option<usize>) -> (String, String) {
+		let (index, material) = match index.map(|index| (index, self.get("materials", index))) {
+			Some((index, Ok(material))) => (index, material),
+			Some((_, Err(e))) => {
+				eprintln!("warning[gltf]: {}, leaving the primitive without a material", e);
+				return (String::new(), String::new());
+			},
+			None => return (String::new(), String::new())
+		};
+
+		let name = material.get("name").and_then(Value::as_str).map(str::to_owned).unwrap_or_else(|| format!("material{}", index));
+
+		let image = material.get("pbrMetallicRoughness")
+			.and_then(|pbr| pbr.get("baseColorTexture"))
+			.and_then(|texture| texture.get("index"))
+			.and_then(Value::as_usize)
+			.and_then(|texture| self.get("textures", texture).ok())
+			.and_then(|texture| texture.get("source"))
+			.and_then(Value::as_usize)
+			.and_then(|source| self.get("images", source).ok().map(|image| (source, image)));
+
+		let texture_name = match image {
+			Some((source, image)) => match image.get("uri").and_then(Value::as_str) {
+				Some(uri) if !uri.starts_with("data:") => texture_name(&decode_uri(uri)),
+				// Embedded images have no file name to go by.
+				_ => image.get("name").and_then(Value::as_str).map(str::to_owned).unwrap_or_else(|| format!("image{}", source))
+			},
+			None => String::new()
+		};
+
+		(name, texture_name)
+	}
+}
+
+/// A node with a mesh.
+struct Instance {
+	node_name: String,
+	node: usize,
+	mesh: usize,
+	/// Plain name of the closest ancestor with a mesh.
+	parent: Option<String>,
+	/// Nodes from the root of the scene down to the node, for evaluating animated transforms.
+	path: Vec<usize>
+}
+
+/// A named empty node, which becomes a tag point.
+struct TagPoint {
+	name: String,
+	/// Plain name of the closest ancestor with a mesh.
+	parent: Option<String>,
+	/// Nodes from the root of the scene down to the tag point node.
+	path: Vec<usize>
+}
+
+/// Walks the node hierarchy of the scene, collecting the meshes and tag points.
+struct SceneGraph<'a> {
+	nodes: &'a [Value],
+	/// Nodes from the root of the scene down to the node being walked.
+	path: Vec<usize>,
+	instances: Vec<Instance>,
+	tag_points: Vec<TagPoint>
+}
+
+impl<'a> SceneGraph<'a> {
+	fn walk(&mut self, index: usize, parent: Option<&str>) -> Result<(), ConversionError> {
+		if self.path.contains(&index) {
+			eprintln!("warning[gltf]: node {} is its own ancestor, ignoring the cycle", index);
+			return Ok(());
+		}
+
+		let node = self.nodes.get(index).ok_or_else(|| ConversionError::gltf(format!("nodes[{}]", index), "does not exist"))?;
+
+		self.path.push(index);
+
+		let name = node.get("name").and_then(Value::as_str);
+		let node_name = name.map(str::to_owned).unwrap_or_else(|| format!("node{}", index));
+		let mesh = node.get("mesh").and_then(Value::as_usize);
+		let children = indices(node.get("children"));
+
+		if node.get("skin").is_some() {
+			eprintln!("warning[gltf]: skinning is not supported, importing node {} in its bind pose", node_name);
+		}
+
+		if let Some(mesh) = mesh {
+			self.instances.push(Instance {
+				node_name: node_name.clone(),
+				node: index,
+				mesh,
+				parent: parent.map(str::to_owned),
+				path: self.path.clone()
+			});
+		} else if name.is_some() && children.is_empty() && node.get("camera").is_none() {
+			self.tag_points.push(TagPoint {
+				name: node_name.clone(),
+				parent: parent.map(str::to_owned),
+				path: self.path.clone()
+			});
+		}
+
+		// Anything further down is nested under this node, if it has a mesh.
+		let base = split_lod(&node_name).0.to_owned();
+		let parent = if mesh.is_some() { Some(&base as &str) } else { parent };
+
+		for child in children {
+			self.walk(child, parent)?;
+		}
+
+		self.path.pop();
+
+		Ok(())
+	}
+}
+
+/// Position and normal offsets of a morph target.
+type Target = (Vec<Vector3<f32>>, Vec<Vector3<f32>>);
+
+/// One triangle primitive of a mesh.
+struct Primitive {
+	/// Level of detail of the mesh.
+	lod: usize,
+	positions: Vec<Vector3<f32>>,
+	/// Empty if the primitive has no normals.
+	normals: Vec<Vector3<f32>>,
+	/// Empty if the primitive has no texture coordinates.
+	texture: Vec<Point2<f32>>,
+	/// Position and normal offsets of each morph target, empty where the target leaves them out.
+	targets: Vec<Target>
+}
+
+/// Whether the animation moves any level of detail or tag point of a model, or changes the weights of its morph targets.
+fn animates(animations: &Animations, lods: &[&Instance], tag_points: &[&TagPoint]) -> bool {
+	lods.iter().any(|instance| animations.animates_path(&instance.path) || animations.get(instance.node, "weights").is_some())
+		|| tag_points.iter().any(|tag_point| animations.animates_path(&tag_point.path))
+}
+
+/// Number of morph targets of a mesh, which every primitive of it should share.
+fn target_count(document: &Document, mesh: usize) -> usize {
+	document.get("meshes", mesh).ok()
+		.and_then(|mesh| elements(mesh.get("primitives")).iter().map(|primitive| elements(primitive.get("targets")).len()).max())
+		.unwrap_or(0)
+}
+
+/// Builds a model out of the mesh of each level of detail, moved by the transform of its node. Each material used by the
+/// primitives becomes a material of the model.
+///
+/// In animated scenes, each frame is a sample of the animation, with the morph targets blended by their animated
+/// weights. Otherwise, the base mesh is the first frame and each morph target is another.
+fn build_model(document: &Document, lods: &[&Instance], tag_points: &[&TagPoint], animations: &Animations, frame_rate: f32, animated: bool, frame_count: usize) -> Result<V2, ConversionError> {
+	let nodes = elements(document.root.get("nodes"));
+
+	let mut primitives = Vec::new();
+	let mut builders = Vec::new();
+
+	for (lod, instance) in lods.iter().enumerate() {
+		let mesh = document.get("meshes", instance.mesh)?;
+
+		// Mirroring transforms turn the faces inside out unless their winding is reversed as well.
+		let mirrored = world_transform(nodes, &instance.path, None).determinant() < 0.0;
+
+		for (primitive_index, primitive) in elements(mesh.get("primitives")).iter().enumerate() {
+			let path = format!("meshes[{}]/primitives[{}]", instance.mesh, primitive_index);
+
+			let mode = primitive.get("mode").and_then(Value::as_usize).unwrap_or(4);
+
+			if mode != 4 {
+				eprintln!("warning[gltf]: {} is not made of triangles (mode {}), skipping it", path, mode);
+				continue;
+			}
+
+			let attributes = primitive.get("attributes");
+			let attribute = |name: &str| attributes.and_then(|attributes| attributes.get(name)).and_then(Value::as_usize);
+
+			let positions = document.vectors(attribute("POSITION").ok_or_else(|| ConversionError::gltf(format!("{}/attributes", path), "missing POSITION"))?)?;
+
+			let normals = match attribute("NORMAL") {
+				Some(accessor) => document.vectors(accessor)?,
+				None => Vec::new()
+			};
+
+			let texture = match attribute("TEXCOORD_0") {
+				Some(accessor) => document.points(accessor)?,
+				None => Vec::new()
+			};
+
+			let mut targets = Vec::new();
+
+			for target in elements(primitive.get("targets")) {
+				let offsets = |name: &str| match target.get(name).and_then(Value::as_usize) {
+					Some(accessor) => document.vectors(accessor),
+					None => Ok(Vec::new())
+				};
+
+				targets.push((offsets("POSITION")?, offsets("NORMAL")?));
+			}
+
+			let indices = match primitive.get("indices").and_then(Value::as_usize) {
+				Some(accessor) => document.accessor(accessor)?.iter().filter_map(|element| element.first().map(|&index| index as usize)).collect::<Vec<_>>(),
+				None => (0..positions.len()).collect()
+			};
+
+			if let Some(&index) = indices.iter().find(|&&index| index >= positions.len()) {
+				return Err(ConversionError::gltf(path, format!("index {} is out of range for {} vertices", index, positions.len())));
+			}
+
+			let source = primitives.len();
+			let builder = MaterialBuilder::select(&mut builders, document.material(primitive.get("material").and_then(Value::as_usize)), lod);
+
+			for triangle in indices.chunks(3).filter(|triangle| triangle.len() == 3) {
+				let triangle = (builder.dedup((source, triangle[0])), builder.dedup((source, triangle[1])), builder.dedup((source, triangle[2])));
+
+				builder.push(lod, triangle, mirrored);
+			}
+
+			primitives.push(Primitive { lod, positions, normals, texture, targets });
+		}
+	}
+
+	let (associations, lod_levels, materials) = layout(builders, lods.len());
+
+	for (lod, triangles) in lod_levels.iter().enumerate() {
+		let vertex_count = associations.iter().filter(|&&(source, _)| primitives[source].lod == lod).count();
+
+		eprintln!("info[gltf]: {} triangles with {} vertices in level of detail {}", triangles.len(), vertex_count, lod);
+	}
+
+	// Every primitive of a mesh has the same morph targets.
+	let target_counts = (0..lods.len())
+		.map(|lod| primitives.iter().filter(|primitive| primitive.lod == lod).map(|primitive| primitive.targets.len()).max().unwrap_or(0))
+		.collect::<Vec<_>>();
+
+	// Weights of the morph targets when they are not animated, from the node or else the mesh.
+	let preset_weights = lods.iter().map(|instance| {
+		floats(nodes.get(instance.node).and_then(|node| node.get("weights")))
+			.or_else(|| document.get("meshes", instance.mesh).ok().and_then(|mesh| floats(mesh.get("weights"))))
+			.unwrap_or_default()
+	}).collect::<Vec<_>>();
+
+	// glTF is Y-up, while CEM is Z-up.
+	let rotation = Matrix4::from_angle_x(Deg(90.0));
+
+	let mut frames = Vec::with_capacity(frame_count);
+	let mut center = Point3::new(0.0, 0.0, 0.0);
+
+	for frame_index in 0..frame_count {
+		let time = animations.start + frame_index as f32 / frame_rate;
+
+		let mut blends = Vec::with_capacity(lods.len());
+
+		for (lod, instance) in lods.iter().enumerate() {
+			let (transform, weights) = if animated {
+				let weights = match animations.get(instance.node, "weights") {
+					Some(channel) => channel.sample(time),
+					None => preset_weights[lod].clone()
+				};
+
+				(world_transform(nodes, &instance.path, Some((animations, time))), weights)
+			} else {
+				let weights = (0..target_counts[lod]).map(|target| if frame_index == target + 1 { 1.0 } else { 0.0 }).collect::<Vec<f32>>();
+
+				(world_transform(nodes, &instance.path, None), weights)
+			};
+
+			let transform = rotation * transform;
+
+			blends.push((transform, normal_matrix(transform), weights));
+		}
+
+		let mut vertices = Vec::with_capacity(associations.len());
+		let mut center_builder = collider::CenterBuilder::begin();
+
+		for &(source, index) in &associations {
+			let primitive = &primitives[source];
+			let blend = &blends[primitive.lod];
+
+			let mut position = primitive.positions[index];
+			let mut normal = primitive.normals.get(index).cloned().unwrap_or(Vector3::new(1.0, 0.0, 0.0));
+
+			for ((positions, normals), &weight) in primitive.targets.iter().zip(blend.2.iter()) {
+				if let Some(&offset) = positions.get(index) {
+					position += offset * weight;
+				}
+
+				if let Some(&offset) = normals.get(index) {
+					normal += offset * weight;
+				}
+			}
+
+			let vertex = v2::Vertex {
+				position: Point3::from_homogeneous(blend.0 * Point3::from_vec(position).to_homogeneous()),
+				normal: (blend.1 * normal).normalize(),
+				texture: primitive.texture.get(index).cloned().unwrap_or(Point2::new(0.0, 0.0))
+			};
+
+			center_builder.update(vertex.position);
+			vertices.push(vertex);
+		}
+
+		let frame_center = center_builder.build();
+
+		let tag_positions = tag_points.iter().map(|tag_point| {
+			let transform = rotation * world_transform(nodes, &tag_point.path, Some((animations, time)));
+
+			Point3::from_homogeneous(transform * Point3::new(0.0, 0.0, 0.0).to_homogeneous())
+		}).collect();
+
+		if frame_index == 0 {
+			center = frame_center;
+		}
+
+		frames.push(v2::Frame::from_vertices(vertices, tag_positions, frame_center));
+	}
+
+	let mut model = V2 {
+		center,
+		materials,
+		lod_levels,
+		tag_points: tag_points.iter().map(|tag_point| tag_point.name.clone()).collect(),
+		frames
+	};
+
+	// Each level of detail is its own mesh, so the vertices they have in common are only merged once they are built.
+	merge_vertices(&mut model);
+
+	Ok(model)
+}
+
+/// Accumulated transform of a node and all of its ancestors.
+fn world_transform(nodes: &[Value], path: &[usize], animated: Option<(&Animations, f32)>) -> Matrix4<f32> {
+	path.iter().fold(Matrix4::identity(), |transform, &index| match nodes.get(index) {
+		Some(node) => transform * node_transform(node, index, animated),
+		None => transform
+	})
+}
+
+/// Transform of a single node, either its matrix or its translation, rotation and scale. Animations only ever change the
+/// latter.
+fn node_transform(node: &Value, index: usize, animated: Option<(&Animations, f32)>) -> Matrix4<f32> {
+	if let Some(m) = floats(node.get("matrix")) {
+		if m.len() == 16 {
+			// Column major, like cgmath.
+			return Matrix4::new(
+				m[0], m[1], m[2], m[3],
+				m[4], m[5], m[6], m[7],
+				m[8], m[9], m[10], m[11],
+				m[12], m[13], m[14], m[15]
+			);
+		}
+	}
+
+	let property = |name: &str, default: Vec<f32>| {
+		let length = default.len();
+
+		animated.and_then(|(animations, time)| animations.get(index, name).map(|channel| channel.sample(time)))
+			.or_else(|| floats(node.get(name)))
+			.filter(|values| values.len() == length)
+			.unwrap_or(default)
+	};
+
+	let translation = property("translation", vec![0.0, 0.0, 0.0]);
+	let rotation = property("rotation", vec![0.0, 0.0, 0.0, 1.0]);
+	let scale = property("scale", vec![1.0, 1.0, 1.0]);
+
+	// glTF quaternions are stored as (x, y, z, w). Interpolating them linearly needs them normalized again.
+	let rotation = Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]).normalize();
+
+	Matrix4::from_translation(Vector3::new(translation[0], translation[1], translation[2]))
+		* Matrix4::from(rotation)
+		* Matrix4::from_nonuniform_scale(scale[0], scale[1], scale[2])
+}
+
+/// The channels of the first animation in the document, by target node and property.
+struct Animations {
+	channels: HashMap<(usize, String), Channel>,
+	start: f32,
+	end: f32
+}
+
+impl Animations {
+	fn get(&self, node: usize, property: &str) -> Option<&Channel> {
+		self.channels.get(&(node, property.to_owned()))
+	}
+
+	/// Number of frames needed to cover every key at the given frame rate, at most `MAX_FRAMES`.
+	fn frame_count(&self, frame_rate: f32) -> Result<usize, ConversionError> {
+		let intervals = ((self.end - self.start) * frame_rate).round();
+
+		// NaN durations are rejected along with the long ones.
+		if intervals.is_nan() || intervals >= MAX_FRAMES as f32 {
+			return Err(ConversionError::gltf("animations[0]", format!(
+				"the animation takes {} seconds, which is more than {} frames at {} frames per second, use a lower --frame-rate", self.end - self.start, MAX_FRAMES, frame_rate
+			)));
+		}
+
+		Ok(intervals as usize + 1)
+	}
+
+	/// Whether the transform of any of the nodes is animated.
+	fn animates_path(&self, path: &[usize]) -> bool {
+		path.iter().any(|&node| ["translation", "rotation", "scale"].iter().any(|property| self.get(node, property).is_some()))
+	}
+}
+
+/// Keyed values of one node property.
+struct Channel {
+	times: Vec<f32>,
+	keys: Vec<Vec<f32>>,
+	step: bool
+}
+
+impl Channel {
+	/// Value at the given time, held before the first and after the last key.
+	fn sample(&self, time: f32) -> Vec<f32> {
+		match self.times.iter().position(|&key| key > time) {
+			Some(0) => self.keys[0].clone(),
+			None => self.keys[self.keys.len() - 1].clone(),
+			Some(next) if self.step => self.keys[next - 1].clone(),
+			Some(next) => {
+				let previous = next - 1;
+				let t = (time - self.times[previous]) / (self.times[next] - self.times[previous]);
+
+				self.keys[previous].iter().zip(self.keys[next].iter()).map(|(a, b)| a + (b - a) * t).collect()
+			}
+		}
+	}
+}
+
+/// Reads the first animation of the document. Models are sampled into frames, so there is no way to keep more than one.
+fn read_animations(document: &Document) -> Result<Animations, ConversionError> {
+	let animations = elements(document.root.get("animations"));
+	let mut channels = HashMap::new();
+
+	if animations.len() > 1 {
+		eprintln!("warning[gltf]: only the first of {} animations is imported", animations.len());
+	}
+
+	if let Some(animation) = animations.first() {
+		let samplers = elements(animation.get("samplers"));
+
+		for (index, channel) in elements(animation.get("channels")).iter().enumerate() {
+			let path = format!("animations[0]/channels[{}]", index);
+			let target = channel.get("target");
+
+			// Extensions can animate things other than nodes.
+			let node = match target.and_then(|target| target.get("node")).and_then(Value::as_usize) {
+				Some(node) => node,
+				None => continue
+			};
+
+			let property = target.and_then(|target| target.get("path")).and_then(Value::as_str).unwrap_or("");
+
+			if !["translation", "rotation", "scale", "weights"].contains(&property) {
+				eprintln!("warning[gltf]: ignoring the animation of {:?} on node {}", property, node);
+				continue;
+			}
+
+			let sampler_index = channel.get("sampler").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(path.clone(), "missing \"sampler\""))?;
+			let sampler_path = format!("animations[0]/samplers[{}]", sampler_index);
+			let sampler = samplers.get(sampler_index).ok_or_else(|| ConversionError::gltf(sampler_path.clone(), "does not exist"))?;
+
+			let input = sampler.get("input").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(sampler_path.clone(), "missing \"input\""))?;
+			let output = sampler.get("output").and_then(Value::as_usize).ok_or_else(|| ConversionError::gltf(sampler_path.clone(), "missing \"output\""))?;
+
+			let (step, cubic) = match sampler.get("interpolation").and_then(Value::as_str).unwrap_or("LINEAR") {
+				"LINEAR" => (false, false),
+				"STEP" => (true, false),
+				"CUBICSPLINE" => (false, true),
+				interpolation => return Err(ConversionError::gltf(sampler_path, format!("unknown interpolation {}", interpolation)))
+			};
+
+			let times = document.accessor(input)?.iter().filter_map(|element| element.first().map(|&time| time as f32)).collect::<Vec<f32>>();
+			let values = document.accessor(output)?.into_iter().flat_map(|element| element.into_iter()).map(|value| value as f32).collect::<Vec<f32>>();
+
+			// Cubic spline keys have an in and out tangent around each value.
+			let parts = if cubic { 3 } else { 1 };
+
+			if times.is_empty() || values.is_empty() || values.len() % (times.len() * parts) != 0 {
+				return Err(ConversionError::gltf(sampler_path, format!("{} output values do not divide into {} keys", values.len(), times.len())));
+			}
+
+			if cubic {
+				eprintln!("warning[gltf]: the cubic spline animation of node {} is sampled linearly", node);
+			}
+
+			let width = values.len() / (times.len() * parts);
+			let keys = values.chunks(width * parts).map(|key| key[(parts / 2) * width..(parts / 2 + 1) * width].to_vec()).collect();
+
+			channels.insert((node, property.to_owned()), Channel { times, keys, step });
+		}
+	}
+
+	let mut start = None;
+	let mut end = None;
+
+	for channel in channels.values() {
+		let (first, last) = (channel.times[0], channel.times[channel.times.len() - 1]);
+
+		start = Some(start.map_or(first, |start: f32| start.min(first)));
+		end = Some(end.map_or(last, |end: f32| end.max(last)));
+	}
+
+	Ok(Animations { channels, start: start.unwrap_or(0.0), end: end.unwrap_or(0.0) })
+}
+
+/// Elements of an optional JSON array.
+fn elements(value: Option<&Value>) -> &[Value] {
+	value.map(Value::elements).unwrap_or(&[])
+}
+
+fn indices(value: Option<&Value>) -> Vec<usize> {
+	elements(value).iter().filter_map(Value::as_usize).collect()
+}
+
+/// An optional JSON array of numbers, such as a translation.
+fn floats(value: Option<&Value>) -> Option<Vec<f32>> {
+	value.and_then(|value| value.elements().iter().map(|element| element.as_f64().map(|number| number as f32)).collect())
+}
+
+/// Decodes the percent escapes of a relative URI, such as `%20` for the spaces in file names.
+fn decode_uri(uri: &str) -> String {
+	let bytes = uri.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut index = 0;
+
+	while index < bytes.len() {
+		if bytes[index] == b'%' {
+			if let Some(byte) = uri.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+				decoded.push(byte);
+				index += 3;
+				continue;
+			}
+		}
+
+		decoded.push(bytes[index]);
+		index += 1;
+	}
+
+	String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes standard or URL safe base64, with or without padding.
+fn base64(encoded: &str) -> Option<Vec<u8>> {
+	let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+	let mut bits = 0u32;
+	let mut count = 0;
+
+	for byte in encoded.bytes().filter(|&byte| byte != b'=') {
+		let value = match byte {
+			b'A'..=b'Z' => byte - b'A',
+			b'a'..=b'z' => byte - b'a' + 26,
+			b'0'..=b'9' => byte - b'0' + 52,
+			b'+' | b'-' => 62,
+			b'/' | b'_' => 63,
+			_ => return None
+		};
+
+		bits = (bits << 6 | value as u32) & 0xFFFFFF;
+		count += 6;
+
+		if count >= 8 {
+			count -= 8;
+			decoded.push((bits >> count) as u8);
+		}
+	}
+
+	Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{base64, decode_uri};
+
+	#[test]
+	fn decodes_base64() {
+		assert_eq!(base64("TWFu"), Some(b"Man".to_vec()));
+		assert_eq!(base64("TWE="), Some(b"Ma".to_vec()));
+		assert_eq!(base64("TQ=="), Some(b"M".to_vec()));
+		assert_eq!(base64(""), Some(Vec::new()));
+		assert_eq!(base64("TWF u"), None);
+	}
+
+	#[test]
+	fn decodes_unpadded_base64() {
+		assert_eq!(base64("TWE"), Some(b"Ma".to_vec()));
+		assert_eq!(base64("TQ"), Some(b"M".to_vec()));
+	}
+
+	#[test]
+	fn decodes_url_safe_base64() {
+		assert_eq!(base64("-_-_"), base64("+/+/"));
+		assert_eq!(base64("-_-_"), Some(vec![0xFB, 0xFF, 0xBF]));
+	}
+
+	#[test]
+	fn decodes_uris() {
+		assert_eq!(decode_uri("model%20one.bin"), "model one.bin");
+		assert_eq!(decode_uri("%C3%A9.bin"), "\u{e9}.bin");
+		// Percent signs that do not start an escape are kept.
+		assert_eq!(decode_uri("100%.bin"), "100%.bin");
+		assert_eq!(decode_uri("%zz%4"), "%zz%4");
+	}
+}
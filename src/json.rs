@@ -0,0 +1,326 @@
+//! Just enough of a JSON reader for glTF documents.
+
+use std::char;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<Value>),
+	/// Members in document order.
+	Object(Vec<(String, Value)>)
+}
+
+impl Value {
+	/// Member of an object, or `None` for missing members and values that are not objects.
+	pub fn get(&self, key: &str) -> Option<&Value> {
+		match *self {
+			Value::Object(ref members) => members.iter().find(|&(name, _)| name == key).map(|(_, value)| value),
+			_ => None
+		}
+	}
+
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Value::Number(number) => Some(number),
+			_ => None
+		}
+	}
+
+	/// The value as an index or count, which has to be a non-negative integer.
+	pub fn as_usize(&self) -> Option<usize> {
+		match *self {
+			Value::Number(number) if number >= 0.0 && number.fract() == 0.0 => Some(number as usize),
+			_ => None
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match *self {
+			Value::String(ref string) => Some(string),
+			_ => None
+		}
+	}
+
+	/// Elements of an array, or nothing for values that are not arrays.
+	pub fn elements(&self) -> &[Value] {
+		match *self {
+			Value::Array(ref elements) => elements,
+			_ => &[]
+		}
+	}
+}
+
+/// Parses a complete JSON document. Errors describe what went wrong and at which byte.
+pub fn parse(text: &str) -> Result<Value, String> {
+	let mut parser = Parser { bytes: text.as_bytes(), position: 0, depth: 0 };
+
+	let value = parser.value()?;
+	parser.skip_whitespace();
+
+	if parser.position != parser.bytes.len() {
+		return Err(parser.error("trailing characters after the document"));
+	}
+
+	Ok(value)
+}
+
+/// Deepest nesting of arrays and objects accepted, so that hostile documents cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+	bytes: &'a [u8],
+	position: usize,
+	/// Number of arrays and objects the parser is currently in.
+	depth: usize
+}
+
+impl<'a> Parser<'a> {
+	fn error(&self, message: &str) -> String {
+		format!("{} at byte {}", message, self.position)
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(&byte) = self.bytes.get(self.position) {
+			match byte {
+				b' ' | b'\t' | b'\n' | b'\r' => self.position += 1,
+				_ => break
+			}
+		}
+	}
+
+	fn expect(&mut self, literal: &[u8]) -> Result<(), String> {
+		if self.bytes[self.position..].starts_with(literal) {
+			self.position += literal.len();
+
+			Ok(())
+		} else {
+			Err(self.error(&format!("expected {}", String::from_utf8_lossy(literal))))
+		}
+	}
+
+	fn value(&mut self) -> Result<Value, String> {
+		self.skip_whitespace();
+
+		match self.bytes.get(self.position) {
+			Some(&b'n') => self.expect(b"null").map(|_| Value::Null),
+			Some(&b't') => self.expect(b"true").map(|_| Value::Bool(true)),
+			Some(&b'f') => self.expect(b"false").map(|_| Value::Bool(false)),
+			Some(&b'"') => self.string().map(Value::String),
+			Some(&b'[') | Some(&b'{') => {
+				if self.depth == MAX_DEPTH {
+					return Err(self.error(&format!("more than {} nested arrays and objects", MAX_DEPTH)));
+				}
+
+				self.depth += 1;
+				let value = if self.bytes[self.position] == b'[' { self.array() } else { self.object() };
+				self.depth -= 1;
+
+				value
+			},
+			Some(&byte) if byte == b'-' || (byte as char).is_ascii_digit() => self.number(),
+			Some(_) => Err(self.error("unexpected character")),
+			None => Err(self.error("unexpected end of the document"))
+		}
+	}
+
+	fn number(&mut self) -> Result<Value, String> {
+		let start = self.position;
+
+		while let Some(&byte) = self.bytes.get(self.position) {
+			match byte {
+				b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E' => self.position += 1,
+				_ => break
+			}
+		}
+
+		// Only ASCII was consumed, so this slice is valid UTF-8.
+		let text = ::std::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+
+		text.parse::<f64>().map(Value::Number).map_err(|_| format!("invalid number {} at byte {}", text, start))
+	}
+
+	fn string(&mut self) -> Result<String, String> {
+		self.expect(b"\"")?;
+
+		let mut string = Vec::new();
+
+		loop {
+			let byte = *self.bytes.get(self.position).ok_or_else(|| self.error("unterminated string"))?;
+			self.position += 1;
+
+			match byte {
+				b'"' => break,
+				b'\\' => {
+					let escape = *self.bytes.get(self.position).ok_or_else(|| self.error("unterminated string"))?;
+					self.position += 1;
+
+					let c = match escape {
+						b'"' => '"',
+						b'\\' => '\\',
+						b'/' => '/',
+						b'b' => '\u{8}',
+						b'f' => '\u{c}',
+						b'n' => '\n',
+						b'r' => '\r',
+						b't' => '\t',
+						b'u' => {
+							let high = self.hex()?;
+
+							// Characters outside the basic multilingual plane are escaped as a surrogate pair.
+							let code = if (0xD800..0xDC00).contains(&high) && self.bytes[self.position..].starts_with(b"\\u") {
+								self.position += 2;
+								let low = self.hex()?;
+
+								0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+							} else {
+								high
+							};
+
+							char::from_u32(code).unwrap_or('\u{FFFD}')
+						},
+						_ => return Err(self.error("invalid escape"))
+					};
+
+					let mut buffer = [0; 4];
+					string.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+				},
+				byte => string.push(byte)
+			}
+		}
+
+		String::from_utf8(string).map_err(|_| self.error("invalid UTF-8 in string"))
+	}
+
+	fn hex(&mut self) -> Result<u32, String> {
+		let digits = self.bytes.get(self.position..self.position + 4).ok_or_else(|| self.error("truncated \\u escape"))?;
+
+		let code = ::std::str::from_utf8(digits).ok()
+			.and_then(|digits| u32::from_str_radix(digits, 16).ok())
+			.ok_or_else(|| self.error("invalid \\u escape"))?;
+
+		self.position += 4;
+
+		Ok(code)
+	}
+
+	fn array(&mut self) -> Result<Value, String> {
+		self.expect(b"[")?;
+
+		let mut elements = Vec::new();
+
+		self.skip_whitespace();
+
+		if self.bytes.get(self.position) == Some(&b']') {
+			self.position += 1;
+			return Ok(Value::Array(elements));
+		}
+
+		loop {
+			elements.push(self.value()?);
+
+			self.skip_whitespace();
+
+			match self.bytes.get(self.position) {
+				Some(&b',') => self.position += 1,
+				Some(&b']') => {
+					self.position += 1;
+					return Ok(Value::Array(elements));
+				},
+				_ => return Err(self.error("expected , or ] in array"))
+			}
+		}
+	}
+
+	fn object(&mut self) -> Result<Value, String> {
+		self.expect(b"{")?;
+
+		let mut members = Vec::new();
+
+		self.skip_whitespace();
+
+		if self.bytes.get(self.position) == Some(&b'}') {
+			self.position += 1;
+			return Ok(Value::Object(members));
+		}
+
+		loop {
+			self.skip_whitespace();
+			let name = self.string()?;
+
+			self.skip_whitespace();
+			self.expect(b":")?;
+
+			members.push((name, self.value()?));
+
+			self.skip_whitespace();
+
+			match self.bytes.get(self.position) {
+				Some(&b',') => self.position += 1,
+				Some(&b'}') => {
+					self.position += 1;
+					return Ok(Value::Object(members));
+				},
+				_ => return Err(self.error("expected , or } in object"))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse, Value, MAX_DEPTH};
+
+	#[test]
+	fn parses_nested_values() {
+		let value = parse(r#" {"a": [1, -2.5e1, true, null], "b": {}, "c": "d"} "#).unwrap();
+
+		assert_eq!(value.get("a"), Some(&Value::Array(vec![Value::Number(1.0), Value::Number(-25.0), Value::Bool(true), Value::Null])));
+		assert_eq!(value.get("b"), Some(&Value::Object(vec![])));
+		assert_eq!(value.get("c").and_then(Value::as_str), Some("d"));
+	}
+
+	#[test]
+	fn parses_escapes() {
+		assert_eq!(parse(r#""\"\\\/\b\f\n\r\t\u00e9""#).unwrap(), Value::String("\"\\/\u{8}\u{c}\n\r\t\u{e9}".to_owned()));
+		assert!(parse(r#""\x""#).is_err());
+		assert!(parse(r#""\u12""#).is_err());
+	}
+
+	#[test]
+	fn parses_surrogate_pairs() {
+		assert_eq!(parse(r#""\ud83d\ude00""#).unwrap(), Value::String("\u{1F600}".to_owned()));
+		// A lone surrogate is not a character.
+		assert_eq!(parse(r#""\ud83d""#).unwrap(), Value::String("\u{FFFD}".to_owned()));
+	}
+
+	#[test]
+	fn parses_numbers() {
+		assert_eq!(parse("0").unwrap(), Value::Number(0.0));
+		assert_eq!(parse("-0.5").unwrap(), Value::Number(-0.5));
+		assert_eq!(parse("1E+3").unwrap(), Value::Number(1000.0));
+		assert_eq!(parse("3").unwrap().as_usize(), Some(3));
+		assert_eq!(parse("3.5").unwrap().as_usize(), None);
+		assert_eq!(parse("-3").unwrap().as_usize(), None);
+		assert!(parse("1-2").is_err());
+		assert!(parse("-").is_err());
+	}
+
+	#[test]
+	fn rejects_truncated_documents() {
+		for text in &["", "[", "[1,", "{", r#"{"a""#, r#"{"a":"#, r#""abc"#, "tru", "[1 2]", "{} x"] {
+			assert!(parse(text).is_err(), "{:?} should not parse", text);
+		}
+	}
+
+	#[test]
+	fn limits_nesting() {
+		let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+
+		assert!(parse(&nested(MAX_DEPTH)).is_ok());
+		assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+		assert!(parse(&"[".repeat(100000)).is_err());
+	}
+}
@@ -0,0 +1,60 @@
+//! Light tag points, which carry their color in the tag point name.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A light tag point, named `light_R_G_B_i_j_k` with the color in 0-255 and three numbers of unknown meaning.
+pub struct Light {
+	pub color: (f32, f32, f32),
+	pub unk: (u32, u32, u32)
+}
+
+impl fmt::Display for Light {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+
+		write!(f, "light_{}_{}_{}_{}_{}_{}", channel(self.color.0), channel(self.color.1), channel(self.color.2), self.unk.0, self.unk.1, self.unk.2)
+	}
+}
+
+impl FromStr for Light {
+	type Err = &'static str;
+
+	fn from_str(definition: &str) -> Result<Self, Self::Err> {
+		fn split(definition: &str) -> Option<(&str, &str, &str, &str, &str, &str)> {
+			let mut split = definition.split('_');
+
+			split.next()?; // "light"
+
+			let r = split.next()?;
+			let g = split.next()?;
+			let b = split.next()?;
+
+			let i = split.next()?;
+			let j = split.next()?;
+			let k = split.next()?;
+
+			Some((r, g, b, i, j, k))
+		}
+
+		let (r, g, b, i, j, k) = split(definition).ok_or("Invalid light definition")?;
+
+		let (r, g, b, i, j, k) = (
+			r.parse::<u32>().map_err(|_| "failed to parse number")?,
+			g.parse::<u32>().map_err(|_| "failed to parse number")?,
+			b.parse::<u32>().map_err(|_| "failed to parse number")?,
+			i.parse::<u32>().map_err(|_| "failed to parse number")?,
+			j.parse::<u32>().map_err(|_| "failed to parse number")?,
+			k.parse::<u32>().map_err(|_| "failed to parse number")?
+		);
+
+		Ok(Light {
+			color: (
+				(r as f32) / 255.0,
+				(g as f32) / 255.0,
+				(b as f32) / 255.0
+			),
+			unk: (i, j, k)
+		})
+	}
+}
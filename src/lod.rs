@@ -0,0 +1,408 @@
+//! Levels of detail beyond the first are exported as separate groups or geometries, named after the
+//! full detail mesh with a `_lod<n>` suffix. The importers use the same convention to put them back.
+//!
+//! Models with a single level of detail can also have lower levels generated for them, see `generate`.
+
+use cem::{v2, V2};
+use cgmath::{Point3, InnerSpace};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Name of the given level of detail of a mesh, level 0 keeps the plain name.
+pub fn lod_name(name: &str, lod: usize) -> String {
+	if lod == 0 {
+		name.to_owned()
+	} else {
+		format!("{}_lod{}", name, lod)
+	}
+}
+
+/// Highest level of detail an importer accepts from a name, higher suffixes are kept as part of the name.
+const MAX_LOD: usize = 15;
+
+/// Splits a name produced by `lod_name` back into the plain name and the level of detail.
+pub fn split_lod(name: &str) -> (&str, usize) {
+	if let Some(position) = name.rfind("_lod") {
+		let digits = &name[position + 4..];
+
+		if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+			if let Ok(lod) = digits.parse::<usize>() {
+				if lod <= MAX_LOD {
+					return (&name[..position], lod);
+				}
+			}
+		}
+	}
+
+	(name, 0)
+}
+
+/// Each generated level of detail keeps about this fraction of the triangles of the level before it.
+const REDUCTION: f64 = 0.5;
+
+/// Adds `levels` lower levels of detail to a model that only has one, by quadric error edge collapse.
+///
+/// Collapses only ever move a vertex onto one of its neighbours, so the vertex buffer is shared by every level
+/// unchanged and each material keeps its vertex range. Vertices on the open border of a mesh, and vertices with the
+/// same position as another vertex (UV seams and material boundaries) never move, so the levels stay free of cracks.
+/// Positions are taken from the first frame.
+pub fn generate(model: &mut V2, levels: usize) {
+	if levels == 0 {
+		return;
+	}
+
+	if model.lod_levels.len() != 1 {
+		eprintln!("warning[lod]: model already has {} levels of detail, not generating more", model.lod_levels.len());
+		return;
+	}
+
+	let positions = match model.frames.first() {
+		Some(frame) => frame.vertices.iter().map(|vertex| vertex.position).collect::<Vec<_>>(),
+		None => {
+			eprintln!("warning[lod]: model has no frames, not generating levels of detail");
+			return;
+		}
+	};
+
+	let mut locked = vec![false; positions.len()];
+
+	{
+		let mut by_position = HashMap::new();
+
+		for (index, position) in positions.iter().enumerate() {
+			by_position.entry((position.x.to_bits(), position.y.to_bits(), position.z.to_bits())).or_insert_with(Vec::new).push(index);
+		}
+
+		for shared in by_position.values().filter(|shared| shared.len() > 1) {
+			for &index in shared {
+				locked[index] = true;
+			}
+		}
+	}
+
+	let mut new_levels = vec![Vec::new(); levels];
+
+	for material in &mut model.materials {
+		material.triangles.truncate(1);
+
+		let selection = match material.triangles.first() {
+			Some(&selection) => selection,
+			None => continue
+		};
+
+		let offset = material.vertex_offset;
+
+		let triangles = model.lod_levels[0][selection.offset as usize..(selection.offset + selection.len) as usize].iter()
+			.map(|&(a, b, c)| [offset + a, offset + b, offset + c])
+			.collect::<Vec<_>>();
+
+		let simplified = Simplifier::new(&positions, &locked, triangles).run(levels);
+
+		for (triangles, level) in simplified.into_iter().zip(new_levels.iter_mut()) {
+			let start = level.len();
+
+			level.extend(triangles.into_iter().map(|[a, b, c]| (a - offset, b - offset, c - offset)));
+
+			material.triangles.push(v2::TriangleSelection {
+				offset: start as u32,
+				len: (level.len() - start) as u32
+			});
+		}
+	}
+
+	for (lod, level) in new_levels.iter().enumerate() {
+		eprintln!("info[lod]: level of detail {} has {} triangles", lod + 1, level.len());
+	}
+
+	model.lod_levels.extend(new_levels);
+}
+
+/// Symmetric 4x4 error quadric, stored as its upper triangle.
+#[derive(Debug, Copy, Clone)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+	fn zero() -> Self {
+		Quadric([0.0; 10])
+	}
+
+	/// Area weighted quadric of the plane through a triangle.
+	fn plane(a: Point3<f32>, b: Point3<f32>, c: Point3<f32>) -> Self {
+		let normal = (b - a).cross(c - a);
+		let length = normal.magnitude() as f64;
+
+		if length == 0.0 || !length.is_finite() {
+			return Quadric::zero();
+		}
+
+		let (x, y, z) = (normal.x as f64 / length, normal.y as f64 / length, normal.z as f64 / length);
+		let d = -(x * a.x as f64 + y * a.y as f64 + z * a.z as f64);
+		let weight = length / 2.0;
+
+		Quadric([
+			weight * x * x, weight * x * y, weight * x * z, weight * x * d,
+			weight * y * y, weight * y * z, weight * y * d,
+			weight * z * z, weight * z * d,
+			weight * d * d
+		])
+	}
+
+	fn add(&mut self, other: &Quadric) {
+		for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+			*a += *b;
+		}
+	}
+
+	fn error(&self, point: Point3<f32>) -> f64 {
+		let q = &self.0;
+		let (x, y, z) = (point.x as f64, point.y as f64, point.z as f64);
+
+		q[0]*x*x + 2.0*q[1]*x*y + 2.0*q[2]*x*z + 2.0*q[3]*x
+			+ q[4]*y*y + 2.0*q[5]*y*z + 2.0*q[6]*y
+			+ q[7]*z*z + 2.0*q[8]*z
+			+ q[9]
+	}
+}
+
+/// Candidate collapse of `from` onto `to`, ordered so that the cheapest comes out of a `BinaryHeap` first.
+struct Collapse {
+	cost: f64,
+	from: u32,
+	to: u32,
+	stamps: (u32, u32)
+}
+
+impl PartialEq for Collapse {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Collapse {
+	fn cmp(&self, other: &Self) -> Ordering {
+		other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+	}
+}
+
+struct Simplifier<'a> {
+	positions: &'a [Point3<f32>],
+	/// Vertices that must not be moved.
+	locked: Vec<bool>,
+	triangles: Vec<[u32; 3]>,
+	alive: Vec<bool>,
+	alive_count: usize,
+	/// Triangles around each vertex, may include dead triangles.
+	adjacent: HashMap<u32, Vec<usize>>,
+	quadrics: HashMap<u32, Quadric>,
+	/// Bumped whenever the quadric of a vertex changes, to invalidate queued collapses.
+	stamps: HashMap<u32, u32>,
+	removed: HashSet<u32>,
+	queue: BinaryHeap<Collapse>
+}
+
+impl<'a> Simplifier<'a> {
+	fn new(positions: &'a [Point3<f32>], locked: &[bool], triangles: Vec<[u32; 3]>) -> Self {
+		let mut adjacent = HashMap::new();
+		let mut quadrics = HashMap::new();
+		let mut edges = HashMap::new();
+
+		for (index, triangle) in triangles.iter().enumerate() {
+			let plane = Quadric::plane(positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize]);
+
+			for corner in 0..3 {
+				let vertex = triangle[corner];
+				let next = triangle[(corner + 1) % 3];
+
+				adjacent.entry(vertex).or_insert_with(Vec::new).push(index);
+				quadrics.entry(vertex).or_insert_with(Quadric::zero).add(&plane);
+				*edges.entry((vertex.min(next), vertex.max(next))).or_insert(0) += 1;
+			}
+		}
+
+		let mut locked = locked.to_vec();
+
+		// Edges used by only one triangle are on the border of the mesh.
+		for (&(a, b), &count) in &edges {
+			if count == 1 {
+				locked[a as usize] = true;
+				locked[b as usize] = true;
+			}
+		}
+
+		let mut simplifier = Simplifier {
+			positions,
+			locked,
+			alive: vec![true; triangles.len()],
+			alive_count: triangles.len(),
+			triangles,
+			adjacent,
+			quadrics,
+			stamps: HashMap::new(),
+			removed: HashSet::new(),
+			queue: BinaryHeap::new()
+		};
+
+		let vertices = simplifier.adjacent.keys().cloned().collect::<Vec<_>>();
+
+		for vertex in vertices {
+			simplifier.queue_around(vertex);
+		}
+
+		simplifier
+	}
+
+	fn stamp(&self, vertex: u32) -> u32 {
+		self.stamps.get(&vertex).cloned().unwrap_or(0)
+	}
+
+	/// Queues every collapse along the edges around a vertex, in both directions.
+	fn queue_around(&mut self, vertex: u32) {
+		let mut neighbours = Vec::new();
+
+		for &triangle in &self.adjacent[&vertex] {
+			if self.alive[triangle] {
+				neighbours.extend(self.triangles[triangle].iter().cloned().filter(|&other| other != vertex));
+			}
+		}
+
+		neighbours.sort();
+		neighbours.dedup();
+
+		for neighbour in neighbours {
+			self.queue_collapse(neighbour, vertex);
+			self.queue_collapse(vertex, neighbour);
+		}
+	}
+
+	fn queue_collapse(&mut self, from: u32, to: u32) {
+		if self.locked[from as usize] {
+			return;
+		}
+
+		let mut quadric = self.quadrics[&from];
+		quadric.add(&self.quadrics[&to]);
+
+		let cost = quadric.error(self.positions[to as usize]);
+
+		self.queue.push(Collapse { cost, from, to, stamps: (self.stamp(from), self.stamp(to)) });
+	}
+
+	/// Whether moving `from` onto `to` would flip or flatten any of the triangles that survive the collapse.
+	fn flips(&self, from: u32, to: u32) -> bool {
+		for &triangle in &self.adjacent[&from] {
+			if !self.alive[triangle] {
+				continue;
+			}
+
+			let corners = self.triangles[triangle];
+
+			if corners.contains(&to) {
+				continue;
+			}
+
+			let position = |vertex: u32, moved: bool| {
+				let vertex = if moved && vertex == from { to } else { vertex };
+
+				self.positions[vertex as usize]
+			};
+
+			let normal = |moved: bool| {
+				let (a, b, c) = (position(corners[0], moved), position(corners[1], moved), position(corners[2], moved));
+
+				(b - a).cross(c - a)
+			};
+
+			if normal(false).dot(normal(true)) <= 0.0 {
+				return true;
+			}
+		}
+
+		false
+	}
+
+	fn collapse(&mut self, from: u32, to: u32) {
+		let triangles = self.adjacent.remove(&from).unwrap_or_default();
+
+		for triangle in triangles {
+			if !self.alive[triangle] {
+				continue;
+			}
+
+			if self.triangles[triangle].contains(&to) {
+				self.alive[triangle] = false;
+				self.alive_count -= 1;
+			} else {
+				for corner in self.triangles[triangle].iter_mut() {
+					if *corner == from {
+						*corner = to;
+					}
+				}
+
+				self.adjacent.get_mut(&to).unwrap().push(triangle);
+			}
+		}
+
+		let quadric = self.quadrics[&from];
+		self.quadrics.get_mut(&to).unwrap().add(&quadric);
+
+		self.removed.insert(from);
+		*self.stamps.entry(to).or_insert(0) += 1;
+
+		self.queue_around(to);
+	}
+
+	fn snapshot(&self) -> Vec<[u32; 3]> {
+		self.triangles.iter().zip(self.alive.iter())
+			.filter(|&(_, &alive)| alive)
+			.map(|(&triangle, _)| triangle)
+			.collect()
+	}
+
+	/// Collapses edges until each level has been reached, returning the triangles of every level.
+	fn run(mut self, levels: usize) -> Vec<Vec<[u32; 3]>> {
+		let original = self.triangles.len();
+		let mut results = Vec::with_capacity(levels);
+
+		while results.len() < levels {
+			let target = (original as f64 * REDUCTION.powi(results.len() as i32 + 1)).ceil() as usize;
+
+			if self.alive_count <= target {
+				results.push(self.snapshot());
+				continue;
+			}
+
+			let candidate = match self.queue.pop() {
+				Some(candidate) => candidate,
+				None => {
+					// Nothing left that can be collapsed, the remaining levels are as simple as this one.
+					results.push(self.snapshot());
+					continue;
+				}
+			};
+
+			let Collapse { from, to, stamps, .. } = candidate;
+
+			if self.removed.contains(&from) || self.removed.contains(&to) || stamps != (self.stamp(from), self.stamp(to)) {
+				continue;
+			}
+
+			let connected = self.adjacent[&from].iter().any(|&triangle| self.alive[triangle] && self.triangles[triangle].contains(&to));
+
+			if !connected || self.flips(from, to) {
+				continue;
+			}
+
+			self.collapse(from, to);
+		}
+
+		results
+	}
+}
@@ -1,3 +1,7 @@
+// structopt-derive 0.1 implements its traits inside a `const` item.
+#![allow(non_local_definitions)]
+
+extern crate byteorder;
 extern crate cem;
 extern crate cgmath;
 extern crate structopt;
@@ -9,49 +13,182 @@ extern crate xml;
 
 mod collada_export;
 mod collada_import;
-
-use wavefront_obj::obj::{self, Object, Primitive, VTNIndex};
+mod error;
+mod gltf_export;
+mod gltf_import;
+mod json;
+mod light;
+mod lod;
+mod mesh_builder;
+mod obj_export;
+mod obj_import;
+mod ply_export;
+mod split;
+mod stl_export;
+mod v1;
+
+use wavefront_obj::obj;
 use collada::document::ColladaDocument;
 use std::fs::File;
+use std::path::Path;
 use std::collections::HashMap;
 use std::io::{self, Read, Write};
-use cem::{ModelHeader, v2, V2, Scene, Model, Encode};
-use cgmath::{Point2, Point3, Vector3, Matrix4, Deg, InnerSpace};
+use std::{fmt, process};
+use cem::{ModelHeader, V2, Scene, Model, Encode};
+use error::ConversionError;
 
 #[derive(StructOpt, Debug)]
 struct Opt {
-	#[structopt(short = "i", long = "input", help = "Input file to convert, default is stdout")]
+	#[structopt(short = "i", long = "input", help = "Input file to convert, default is stdin")]
 	input: Option<String>,
-	#[structopt(short = "g", long = "iformat", help = "Format to use for the input")]
+	#[structopt(short = "g", long = "iformat", help = "Format to use for the input, detected from the input if not given")]
 	input_format: Option<String>,
-	#[structopt(short = "f", long = "format", help = "Format to use as the output")]
-	format: String,
+	#[structopt(short = "f", long = "format", help = "Format to use as the output, inferred from the output file extension if not given")]
+	format: Option<String>,
 	#[structopt(short = "n", long = "frame", help = "Frame number in the CEM file to extract")]
 	frame_index: Option<usize>,
+	#[structopt(long = "lod", help = "Level of detail to export to PLY or STL, default is 0")]
+	lod: Option<usize>,
+	#[structopt(long = "all-frames", help = "Export every frame to a numbered OBJ sequence (name_0000.obj, ...), or import such a sequence as frames")]
+	all_frames: bool,
+	#[structopt(long = "split", help = "Split models over the vertex limit of the game into submodels when writing CEMv2")]
+	split: bool,
+	#[structopt(long = "generate-lods", help = "Number of lower levels of detail to generate when writing a CEMv2 model with only one, each with half the triangles of the last")]
+	generate_lods: Option<usize>,
+	#[structopt(long = "frame-rate", help = "Frames per second of animations, used to sample COLLADA and glTF animations on import and to time the frames on export, default is 30")]
+	frame_rate: Option<f32>,
+	#[structopt(long = "texture-ext", help = "File extension of the textures referenced by exported materials, default is tga")]
+	texture_extension: Option<String>,
 	#[structopt(help = "Output file, default is stdout")]
 	output: Option<String>
 }
 
 enum Format {
-	Cem { version: (u16, u16) },
-	Obj { frame_index: usize },
-	Collada
+	Cem { version: (u16, u16), generate_lods: usize, split: bool },
+	Obj { frame_index: usize, texture_extension: String, all_frames: bool },
+	Collada { frame_rate: f32, texture_extension: String },
+	Gltf { binary: bool, frame_rate: f32, texture_extension: String },
+	Ply { frame_index: usize, lod: usize, binary: bool },
+	Stl { frame_index: usize, lod: usize }
 }
 
 impl Format {
-	fn parse(format: &str, frame_index: Option<usize>) -> Option<Self> {
-		let frame_index = frame_index.unwrap_or(0);
+	fn parse(format: &str, opt: &Opt) -> Option<Self> {
+		let frame_index = opt.frame_index.unwrap_or(0);
+		let lod = opt.lod.unwrap_or(0);
+		let generate_lods = opt.generate_lods.unwrap_or(0);
+		let texture_extension = opt.texture_extension.as_ref().map(|s| s.trim_start_matches('.')).unwrap_or("tga").to_owned();
 
 		Some(match format {
-			"cem1.3" => Format::Cem { version: (1 ,3) },
-			"cem2" => Format::Cem { version: (2, 0) },
-			"cem" => Format::Cem { version: (2, 0)},
-			"ssmf" => Format::Cem { version: (2, 0) },
-			"obj" => Format::Obj { frame_index },
-			"collada" => Format::Collada,
+			"cem1.3" => Format::Cem { version: (1 ,3), generate_lods, split: opt.split },
+			"cem2" => Format::Cem { version: (2, 0), generate_lods, split: opt.split },
+			"cem" => Format::Cem { version: (2, 0), generate_lods, split: opt.split },
+			"ssmf" => Format::Cem { version: (2, 0), generate_lods, split: opt.split },
+			"obj" => Format::Obj { frame_index, texture_extension, all_frames: opt.all_frames },
+			"collada" => Format::Collada { frame_rate: opt.frame_rate.unwrap_or(30.0), texture_extension },
+			"gltf" => Format::Gltf { binary: false, frame_rate: opt.frame_rate.unwrap_or(30.0), texture_extension },
+			"glb" => Format::Gltf { binary: true, frame_rate: opt.frame_rate.unwrap_or(30.0), texture_extension },
+			"ply" => Format::Ply { frame_index, lod, binary: false },
+			"ply-binary" => Format::Ply { frame_index, lod, binary: true },
+			"stl" => Format::Stl { frame_index, lod },
 			_ => return None
 		})
 	}
+
+	/// Guesses the format of an input file, first from its contents and then from its extension.
+	fn detect(data: &[u8], path: Option<&str>, opt: &Opt) -> Option<Self> {
+		if data.starts_with(b"SSMF") {
+			if let Ok(header) = ModelHeader::read(&mut &data[..]) {
+				return Some(Format::Cem { version: (header.major, header.minor), generate_lods: 0, split: false });
+			}
+		}
+
+		// Only look at the start of the file, the format is apparent from the first few lines.
+		let truncated = data.len() > 4096;
+		let text = String::from_utf8_lossy(&data[..data.len().min(4096)]);
+		let text = text.trim_start_matches('\u{feff}').trim_start();
+
+		if text.starts_with("<COLLADA") || (text.starts_with("<?xml") && text.contains("<COLLADA")) {
+			return Format::parse("collada", opt);
+		}
+
+		if data.starts_with(b"glTF") {
+			return Format::parse("glb", opt);
+		}
+
+		if text.starts_with('{') && text.contains("\"asset\"") {
+			return Format::parse("gltf", opt);
+		}
+
+		if looks_like_obj(text, truncated) {
+			return Format::parse("obj", opt);
+		}
+
+		path.and_then(|path| Format::from_extension(path, opt))
+	}
+
+	fn from_extension(path: &str, opt: &Opt) -> Option<Self> {
+		let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+
+		match &extension as &str {
+			"cem" | "ssmf" => Format::parse("cem", opt),
+			"obj" => Format::parse("obj", opt),
+			"dae" => Format::parse("collada", opt),
+			"gltf" => Format::parse("gltf", opt),
+			"glb" => Format::parse("glb", opt),
+			"ply" => Format::parse("ply", opt),
+			"stl" => Format::parse("stl", opt),
+			_ => None
+		}
+	}
+}
+
+/// Checks that every line in the text starts with a Wavefront OBJ keyword, and that there is at least one vertex.
+fn looks_like_obj(text: &str, truncated: bool) -> bool {
+	let mut lines = text.lines().collect::<Vec<_>>();
+
+	// The last line may have been cut off in the middle of a keyword.
+	if truncated {
+		lines.pop();
+	}
+
+	let mut has_vertex = false;
+
+	for line in lines {
+		let line = line.trim();
+
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		match line.split_whitespace().next() {
+			Some("v") => has_vertex = true,
+			Some("vt") | Some("vn") | Some("vp") | Some("f") | Some("l") | Some("p") | Some("o") | Some("g") | Some("s") | Some("mtllib") | Some("usemtl") => (),
+			_ => return false
+		}
+	}
+
+	has_vertex
+}
+
+impl fmt::Display for Format {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Format::Cem { version: (major, minor), .. } => write!(f, "cem{}.{}", major, minor),
+			Format::Obj { .. } => write!(f, "obj"),
+			Format::Collada { .. } => write!(f, "collada"),
+			Format::Gltf { binary: false, .. } => write!(f, "gltf"),
+			Format::Gltf { binary: true, .. } => write!(f, "glb"),
+			Format::Ply { binary: false, .. } => write!(f, "ply"),
+			Format::Ply { binary: true, .. } => write!(f, "ply-binary"),
+			Format::Stl { .. } => write!(f, "stl")
+		}
+	}
+}
+
+fn exit_with(e: ConversionError) -> ! {
+	eprintln!("error: {}", e);
+	process::exit(e.exit_code())
 }
 
 fn main() {
@@ -59,275 +196,252 @@ fn main() {
 
 	let opt = Opt::from_args();
 
-	let format = match Format::parse(&opt.format, opt.frame_index) {
-		Some(format) => format,
-		None => {
-			eprintln!("Unrecognized output format {:?}", opt.format);
-			return;
-		}
+	let format = match (opt.format.as_ref(), opt.output.as_ref()) {
+		(Some(name), _) => match Format::parse(name, &opt) {
+			Some(format) => format,
+			None => exit_with(ConversionError::UnrecognizedFormat(name.clone()))
+		},
+		(None, Some(path)) => match Format::from_extension(path, &opt) {
+			Some(format) => format,
+			None => exit_with(ConversionError::UndetectedFormat(format!("cannot infer the output format from the extension of {}, use --format", path)))
+		},
+		(None, None) => exit_with(ConversionError::UndetectedFormat("--format is required when writing to stdout".to_string()))
 	};
 
-	let input_format = match Format::parse(opt.input_format.as_ref().map(|s| s as &str).unwrap_or(""), opt.frame_index) {
-		Some(format) => format,
-		None => Format::Cem { version: (2, 0) }
+	let mut input = Vec::new();
+
+	let read = match opt.input {
+		Some(ref path) => File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+		None => io::stdin().read_to_end(&mut input)
 	};
 
-	let stdin = io::stdin();
-	let stdout = io::stdout();
-
-	let result = match (opt.input, opt.output) {
-		(None, None) => convert (
-			stdin.lock(),
-			stdout.lock(),
-			input_format,
-			format
-		),
-		(None, Some(path)) => convert (
-			stdin.lock(),
-			match File::create(&path) {
-				Ok(file) => file,
-				Err(e) => {
-					eprintln!("error: failed to create the output file at {} ({})", path, e);
-					return
-				}
-			},
-			input_format,
-			format
-		),
-		(Some(path), None) => convert (
-			match File::open(&path) {
-				Ok(file) => file,
-				Err(e) => {
-					eprintln!("error: failed to open the input file at {} ({})", path, e);
-					return
-				}
-			},
-			stdout.lock(),
-			input_format,
-			format
-		),
-		(Some(input), Some(output)) => convert (
-			match File::open(&input) {
-				Ok(file) => file,
-				Err(e) => {
-					eprintln!("error: failed open the input file at {} ({})", input, e);
-					return
-				}
-			},
-			match File::create(&output) {
-				Ok(file) => file,
-				Err(e) => {
-					eprintln!("error: failed to create the output file at {} ({})", output, e);
-					return
-				}
-			},
-			input_format,
-			format
-		)
+	if let Err(e) = read {
+		eprintln!("error: failed to read the input file at {} ({})", opt.input.as_ref().map(|s| s as &str).unwrap_or("<stdin>"), e);
+		process::exit(ConversionError::Io(e).exit_code());
+	}
+
+	let input_format = match opt.input_format {
+		Some(ref name) => match Format::parse(name, &opt) {
+			Some(format) => format,
+			None => exit_with(ConversionError::UnrecognizedFormat(name.clone()))
+		},
+		None => match Format::detect(&input, opt.input.as_ref().map(|s| s as &str), &opt) {
+			Some(format) => format,
+			None => exit_with(ConversionError::UndetectedFormat("the input is not recognizably CEM, OBJ, COLLADA, or glTF, use --iformat".to_string()))
+		}
 	};
 
+	let result = convert(&input[..], opt.input.as_ref().map(|s| s as &str), opt.output.as_ref().map(|s| s as &str), input_format, format);
+
 	if let Err(e) = result {
 		eprintln!("error: conversion failed: {}", e);
+		process::exit(e.exit_code());
 	}
 }
 
-fn convert<I, O>(mut i: I, mut o: O, input_format: Format, format: Format) -> io::Result<()> where I: Read, O: Write {
-	match (input_format, format) {
-		(Format::Obj { frame_index: _ }, Format::Cem { version: (2, 0) }) => {
+fn convert<I>(mut i: I, input_path: Option<&str>, output_path: Option<&str>, input_format: Format, format: Format) -> Result<(), ConversionError> where I: Read {
+	let mut scene = match input_format {
+		Format::Cem { .. } => read_cem(&mut i)?,
+		Format::Obj { all_frames, .. } => {
+			let mut buffer = String::new();
+			i.read_to_string(&mut buffer)?;
+
+			let obj = parse_obj(buffer)?;
+
+			let textures = match obj.material_library {
+				Some(ref library) => obj_import::load_mtl(input_path, library),
+				None => HashMap::new()
+			};
+
+			let mut model = obj_import::convert(&obj.objects, Some(&textures));
+
+			if all_frames {
+				let first = input_path.map(Path::new).ok_or_else(|| ConversionError::InvalidOptions("--all-frames needs an input file to find the rest of the OBJ sequence".to_string()))?;
+
+				let mut next = obj_import::next_in_sequence(first).ok_or_else(|| ConversionError::InvalidOptions(
+					format!("--all-frames needs the input to be the first file of a numbered sequence such as name_0000.obj, not {}", first.display())
+				))?;
+
+				while next.exists() {
+					let mut buffer = String::new();
+					File::open(&next)?.read_to_string(&mut buffer)?;
+
+					let frame = obj_import::convert(&parse_obj(buffer)?.objects, None);
+					obj_import::append_frame(&mut model, frame)?;
+
+					next = match obj_import::next_in_sequence(&next) {
+						Some(path) => path,
+						None => break
+					};
+				}
+			}
+
+			Scene::root(model)
+		},
+		Format::Collada { frame_rate, .. } => {
+			if frame_rate.is_nan() || frame_rate <= 0.0 {
+				return Err(ConversionError::InvalidOptions(format!("--frame-rate has to be positive, not {}", frame_rate)));
+			}
+
 			let mut buffer = String::new();
 			i.read_to_string(&mut buffer)?;
 
-			let obj = obj::parse(buffer).map_err(
-				|parse| io::Error::new(io::ErrorKind::InvalidData, format!("Error in OBJ file on line {}: {}", parse.line_number, parse.message))
-			)?;
+			let xml = buffer.parse::<xml::Element>().map_err(|e| ConversionError::collada("COLLADA", format!("{}", e)))?;
+
+			collada_import::convert(ColladaDocument { root_element: xml }, frame_rate)?
+		},
+		Format::Gltf { frame_rate, .. } => {
+			if frame_rate.is_nan() || frame_rate <= 0.0 {
+				return Err(ConversionError::InvalidOptions(format!("--frame-rate has to be positive, not {}", frame_rate)));
+			}
 
-			let model = obj_to_cem(&obj.objects);
+			let mut buffer = Vec::new();
+			i.read_to_end(&mut buffer)?;
 
-			Scene::root(model).write(&mut o)
+			gltf_import::convert(&buffer, input_path, frame_rate)?
 		},
-		(Format::Cem { version: (2, 0) }, Format::Cem { version: (2, 0) }) => {
-			let header = ModelHeader::read(&mut i)?;
+		Format::Ply { .. } | Format::Stl { .. } => return Err(ConversionError::UnsupportedConversion { from: input_format.to_string(), to: format.to_string() })
+	};
+
+	let output_path = output_path.map(Path::new);
 
-			if header == V2::HEADER {
-				Scene::<V2>::read_without_header(&mut i)?.write(&mut o)
+	match format {
+		Format::Cem { version: (2, 0), generate_lods, split } => {
+			if split {
+				split::split_scene(&mut scene);
 			} else {
-				unimplemented!("Cannot rewrite non-CEMv2 files yet.")
+				split::warn_oversized(&scene);
 			}
+
+			generate_scene_lods(&mut scene, generate_lods);
+
+			let mut buffer = Vec::new();
+			scene.write(&mut buffer)?;
+
+			write_output(output_path, &buffer)
 		},
-		(Format::Cem { version: (_, _) }, Format::Obj { frame_index }) => {
-			let header = ModelHeader::read(&mut i)?;
+		Format::Obj { frame_index, texture_extension, all_frames } => {
+			if frame_index >= scene.model.frames.len() {
+				return Err(ConversionError::FrameOutOfRange { index: frame_index, frames: scene.model.frames.len() });
+			}
+
+			// The material library has to be a separate file, so it can only be written next to an output file.
+			let material_library = match output_path {
+				Some(path) => {
+					let path = path.with_extension("mtl");
 
-			if header == V2::HEADER {
-				let scene = Scene::<V2>::read_without_header(&mut i)?;
+					write_output(Some(&path), obj_export::write_mtl(&scene, &texture_extension).as_bytes())?;
 
-				if frame_index >= scene.model.frames.len() {
-					return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Tried to extract frame index {} from a CEM file that only has {} frames", frame_index, scene.model.frames.len())));
+					path.file_name().and_then(|name| name.to_str()).map(str::to_owned)
+				},
+				None => {
+					eprintln!("warning[obj]: not writing a material library when writing to stdout");
+
+					None
 				}
+			};
+
+			let material_library = material_library.as_ref().map(|s| s as &str);
 
-				let buffer = cem2_to_obj(scene.model, frame_index);
+			if all_frames {
+				let path = output_path.ok_or_else(|| ConversionError::InvalidOptions("--all-frames writes one file per frame, so it needs an output file".to_string()))?;
 
-				o.write_all(buffer.as_bytes())
+				for frame_index in 0..scene.model.frames.len() {
+					let buffer = obj_export::convert(&scene, frame_index, material_library);
+
+					write_output(Some(&obj_export::frame_path(path, frame_index)), buffer.as_bytes())?;
+				}
+
+				Ok(())
 			} else {
-				unimplemented!("Cannot convert non-CEMv2 files to OBJ yet.")
+				let buffer = obj_export::convert(&scene, frame_index, material_library);
+
+				write_output(output_path, buffer.as_bytes())
 			}
 		},
-		(Format::Cem { version: (_, _) }, Format::Collada) => {
-			let header = ModelHeader::read(&mut i)?;
-
-			if header == V2::HEADER {
-				let scene = Scene::<V2>::read_without_header(&mut i)?;
+		Format::Collada { frame_rate, texture_extension } => {
+			if frame_rate.is_nan() || frame_rate <= 0.0 {
+				return Err(ConversionError::InvalidOptions(format!("--frame-rate has to be positive, not {}", frame_rate)));
+			}
 
-				let buffer = collada_export::convert(scene);
+			let buffer = collada_export::convert(scene, frame_rate, &texture_extension);
 
-				o.write_all(buffer.as_bytes())
-			} else {
-				unimplemented!("Cannot convert non-CEMv2 files to COLLADA yet.")
+			write_output(output_path, buffer.as_bytes())
+		},
+		Format::Gltf { binary, frame_rate, texture_extension } => {
+			if frame_rate.is_nan() || frame_rate <= 0.0 {
+				return Err(ConversionError::InvalidOptions(format!("--frame-rate has to be positive, not {}", frame_rate)));
 			}
+
+			write_output(output_path, &gltf_export::convert(&scene, frame_rate, &texture_extension, binary)?)
 		},
-		(Format::Collada, Format::Cem { version: (2, 0) }) => {
-			let mut buffer = String::new();
-			i.read_to_string(&mut buffer)?;
+		Format::Ply { frame_index, lod, binary } => {
+			check_selection(&scene, frame_index, lod)?;
 
-			let xml = buffer.parse::<xml::Element>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))?;
-			let model = collada_import::convert(ColladaDocument { root_element: xml });
+			write_output(output_path, &ply_export::convert(&scene, frame_index, lod, binary))
+		},
+		Format::Stl { frame_index, lod } => {
+			check_selection(&scene, frame_index, lod)?;
 
-			Scene::root(model).write(&mut o)
+			write_output(output_path, &stl_export::convert(&scene, frame_index, lod))
 		},
-		_ => unimplemented!()
+		format => Err(ConversionError::UnsupportedConversion { from: input_format.to_string(), to: format.to_string() })
 	}
 }
 
-fn obj_to_cem(i: &[Object]) -> V2 {
-	let mut triangles = Vec::new();
-	let mut vertices = Vec::new();
-
-	let transformation = Matrix4::from_angle_x(Deg(90.0));
-
-	{
-		let mut vertex_associations = HashMap::new();
-
-		let mut resolve_index = |i: &Object, idx: usize, v: VTNIndex| {
-			*vertex_associations.entry((idx, v)).or_insert_with(|| {
-				let index = vertices.len();
-
-				let position = i.vertices[v.0];
-				let texture = v.1.map(|index| i.tex_vertices[index]).unwrap_or(obj::TVertex { u: 0.0, v: 0.0, w: 0.0 });
-				let normal = v.2.map(|index| i.normals[index]).unwrap_or(obj::Vertex { x: 1.0, y: 0.0, z: 0.0 });
-
-				let normal = Vector3 { x: normal.x as f32, y: normal.y as f32, z: normal.z as f32 };
-				let position = Point3 { x: position.x as f32, y: position.y as f32, z: position.z as f32 };
-
-				let normal = (transformation * normal.normalize().extend(0.0)).truncate();
-				let position = Point3::from_homogeneous(transformation * position.to_homogeneous());
-
-				vertices.push(v2::Vertex {
-					position,
-					normal,
-					texture: Point2 { x: texture.u as f32, y: 1.0 - texture.v as f32 },
-				});
-
-				index
-			})
-		};
-
-		for (idx, i) in i.iter().enumerate() {
-			for geometry in &i.geometry {
-				for primitive in geometry.shapes.iter().map(|shape| shape.primitive) {
-					match primitive {
-						Primitive::Triangle(v0, v1, v2) => {
-							triangles.push((
-								resolve_index(i, idx, v0) as u32,
-								resolve_index(i, idx, v1) as u32,
-								resolve_index(i, idx, v2) as u32
-							));
-						},
-						_ => () // Skip lines and points, not supported.
-					}
-				}
-			}
-		}
+/// Checks that the root model has the frame and level of detail to export.
+fn check_selection(scene: &Scene<V2>, frame_index: usize, lod: usize) -> Result<(), ConversionError> {
+	if frame_index >= scene.model.frames.len() {
+		return Err(ConversionError::FrameOutOfRange { index: frame_index, frames: scene.model.frames.len() });
 	}
 
-	// It appears that there is some limit on the vertex count. This needs to be investigated further, but it appears that
-	// adding more than 2442 instantly crashes the game on model load.
-	if vertices.len() > 2442 {
-		eprintln!("warning[cem]: Vertex count exceeds 2442, this will most likely crash the game. You have been warned.");
-		eprintln!("warning[cem]: {} vertices, {} triangles", vertices.len(), triangles.len());
+	if lod >= scene.model.lod_levels.len() {
+		return Err(ConversionError::InvalidOptions(format!("--lod {} is out of range, the model only has {} levels of detail", lod, scene.model.lod_levels.len())));
 	}
 
-	// Create the model
-
-	let mut center_builder = ::cem::collider::CenterBuilder::begin();
+	Ok(())
+}
 
-	for vertex in &vertices {
-		center_builder.update(vertex.position);
-	}
+fn generate_scene_lods(scene: &mut Scene<V2>, levels: usize) {
+	lod::generate(&mut scene.model, levels);
 
-	let center = center_builder.build();
-
-	V2 {
-		center,
-		materials: vec![v2::Material {
-			name: "".to_string(),
-			texture: 0,
-			triangles: vec![
-				v2::TriangleSelection {
-					offset: 0,
-					len: triangles.len() as u32
-				}
-			],
-			vertex_offset: 0,
-			vertex_count: vertices.len() as u32,
-			texture_name: "".to_string()
-		}],
-		lod_levels: vec![
-			triangles
-		],
-		tag_points: vec![],
-		frames: vec![
-			v2::Frame::from_vertices(vertices, vec![], center)
-		]
+	for child in &mut scene.children {
+		generate_scene_lods(child, levels);
 	}
 }
 
-fn cem2_to_obj(cem: V2, frame_index: usize) -> String {
-	use std::fmt::Write;
+/// Writes a finished output file, or stdout if there is no output path.
+fn write_output(path: Option<&Path>, data: &[u8]) -> Result<(), ConversionError> {
+	match path {
+		Some(path) => {
+			let mut file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("failed to create the output file at {} ({})", path.display(), e)))?;
 
-	let triangle_data = &cem.lod_levels[0];
-	let frame = &cem.frames[frame_index];
-
-	let mut string = String::new();
-
-	let transformation = Matrix4::from_angle_x(Deg(-90.0));
-
-	for &v2::Vertex { position, normal, texture } in frame.vertices.iter() {
-
-		let normal = (transformation * normal.normalize().extend(0.0)).truncate();
-		let position = Point3::from_homogeneous(transformation * position.to_homogeneous());
+			Ok(file.write_all(data)?)
+		},
+		None => {
+			let stdout = io::stdout();
+			let mut stdout = stdout.lock();
 
-		writeln!(string, "v {} {} {}", position.x, position.y, position.z).unwrap();
-		writeln!(string, "vn {} {} {}", normal.x, normal.y, normal.z).unwrap();
-		writeln!(string, "vt {} {}", texture.x, 1.0 - texture.y).unwrap();
+			Ok(stdout.write_all(data)?)
+		}
 	}
+}
 
-	for &v2::Material { ref name, texture, ref triangles, vertex_offset, vertex_count: _vertex_count, ref texture_name } in &cem.materials {
-		let triangle_slice = triangles[0];
-
-		writeln!(string, "# name: {}, texture: {}, texture_name: {}", name, texture, texture_name).unwrap();
-
-		for index in 0..triangle_slice.len {
-			let index = index + triangle_slice.offset;
-			let triangle = &triangle_data[index as usize];
+fn parse_obj(buffer: String) -> Result<obj::ObjSet, ConversionError> {
+	obj::parse(buffer).map_err(
+		|parse| ConversionError::MalformedObj { line: parse.line_number, message: parse.message }
+	)
+}
 
-			let indices = (
-				vertex_offset + triangle.0 + 1,
-				vertex_offset + triangle.1 + 1,
-				vertex_offset + triangle.2 + 1
-			);
+/// Reads a CEM file of any supported version, upgrading older versions to CEMv2.
+fn read_cem<I>(i: &mut I) -> Result<Scene<V2>, ConversionError> where I: Read {
+	let header = ModelHeader::read(i)?;
 
-			writeln!(string, "f {}/{}/{} {}/{}/{} {}/{}/{}", indices.0, indices.0, indices.0, indices.1, indices.1, indices.1, indices.2, indices.2, indices.2).unwrap();
-		}
+	if header == V2::HEADER {
+		Ok(Scene::<V2>::read_without_header(i)?)
+	} else if header == v1::HEADER {
+		Ok(v1::read_without_header(i)?)
+	} else {
+		Err(ConversionError::UnsupportedCemVersion { major: header.major, minor: header.minor })
 	}
-
-	string
-}
\ No newline at end of file
+}
@@ -0,0 +1,164 @@
+//! Helpers shared by the importers for building CEM models out of the meshes of other formats.
+
+use cem::v2;
+use cgmath::{Matrix, Matrix3, Matrix4, SquareMatrix};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Most frames an animation is sampled into, as every frame holds the whole mesh.
+pub const MAX_FRAMES: usize = 1024;
+
+/// Triangles of each level of detail.
+pub type LodLevels = Vec<Vec<(u32, u32, u32)>>;
+
+/// The vertices and triangles of one material of a model that is being built. Vertices are identified by where they
+/// come from in the source document, so that each is only added once.
+pub struct MaterialBuilder<V> {
+	/// Name and texture name.
+	material: (String, String),
+	vertices: Vec<V>,
+	reverse: HashMap<V, u32>,
+	/// Triangles of each level of detail, indexing the vertices of this material.
+	triangles: LodLevels
+}
+
+impl<V> MaterialBuilder<V> where V: Copy + Eq + Hash {
+	/// The builder of a material, which is added if the model does not use the material yet.
+	pub fn select(builders: &mut Vec<Self>, material: (String, String), lod: usize) -> &mut Self {
+		let index = match builders.iter().position(|builder| builder.material == material) {
+			Some(index) => index,
+			None => {
+				builders.push(MaterialBuilder { material, vertices: Vec::new(), reverse: HashMap::new(), triangles: Vec::new() });
+				builders.len() - 1
+			}
+		};
+
+		let builder = &mut builders[index];
+
+		while builder.triangles.len() <= lod {
+			builder.triangles.push(Vec::new());
+		}
+
+		builder
+	}
+
+	pub fn dedup(&mut self, vertex: V) -> u32 {
+		let vertices = &mut self.vertices;
+
+		*self.reverse.entry(vertex).or_insert_with(|| {
+			vertices.push(vertex);
+
+			(vertices.len() - 1) as u32
+		})
+	}
+
+	/// Adds a triangle to a level of detail, with its winding reversed if it comes from mirrored geometry.
+	pub fn push(&mut self, lod: usize, (a, b, c): (u32, u32, u32), mirrored: bool) {
+		self.triangles[lod].push(if mirrored { (a, c, b) } else { (a, b, c) });
+	}
+
+	pub fn triangle_count(&self, lod: usize) -> usize {
+		self.triangles.get(lod).map(Vec::len).unwrap_or(0)
+	}
+
+	pub fn vertex_count(&self) -> usize {
+		self.vertices.len()
+	}
+}
+
+/// Lays out the materials one after another, in the vertex buffer and in each level of detail. Returns the source of
+/// each vertex in the vertex buffer, along with the triangles and the materials of the model.
+pub fn layout<V>(builders: Vec<MaterialBuilder<V>>, lod_count: usize) -> (Vec<V>, LodLevels, Vec<v2::Material>) {
+	let mut associations = Vec::new();
+	let mut lod_levels = vec![Vec::new(); lod_count];
+	let mut materials = Vec::with_capacity(builders.len());
+
+	for MaterialBuilder { material: (name, texture_name), vertices, triangles, .. } in builders {
+		let mut selections = Vec::with_capacity(lod_levels.len());
+
+		for (lod, level) in lod_levels.iter_mut().enumerate() {
+			let triangles = triangles.get(lod).map(Vec::as_slice).unwrap_or(&[]);
+
+			selections.push(v2::TriangleSelection {
+				offset: level.len() as u32,
+				len: triangles.len() as u32
+			});
+
+			level.extend_from_slice(triangles);
+		}
+
+		materials.push(v2::Material {
+			name,
+			texture: 0,
+			triangles: selections,
+			vertex_offset: associations.len() as u32,
+			vertex_count: vertices.len() as u32,
+			texture_name
+		});
+
+		associations.extend(vertices);
+	}
+
+	(associations, lod_levels, materials)
+}
+
+/// Merges the vertices of each material that are the same in every frame, such as the vertices that levels of detail
+/// read from separate copies of the same geometry.
+pub fn merge_vertices(model: &mut v2::V2) {
+	if model.frames.is_empty() {
+		return;
+	}
+
+	// Vertices that are kept, by their index in the old vertex buffer.
+	let mut kept = Vec::new();
+
+	for material in &mut model.materials {
+		let offset = kept.len() as u32;
+		let mut reverse = HashMap::new();
+		let mut remap = Vec::with_capacity(material.vertex_count as usize);
+
+		for vertex in material.vertex_offset..material.vertex_offset + material.vertex_count {
+			let key = model.frames.iter().flat_map(|frame| {
+				let v2::Vertex { position, normal, texture } = frame.vertices[vertex as usize];
+
+				vec![position.x, position.y, position.z, normal.x, normal.y, normal.z, texture.x, texture.y]
+			}).map(f32::to_bits).collect::<Vec<_>>();
+
+			remap.push(*reverse.entry(key).or_insert_with(|| {
+				kept.push(vertex as usize);
+
+				kept.len() as u32 - 1 - offset
+			}));
+		}
+
+		for (level, selection) in model.lod_levels.iter_mut().zip(&material.triangles) {
+			for triangle in &mut level[selection.offset as usize..(selection.offset + selection.len) as usize] {
+				*triangle = (remap[triangle.0 as usize], remap[triangle.1 as usize], remap[triangle.2 as usize]);
+			}
+		}
+
+		material.vertex_offset = offset;
+		material.vertex_count = kept.len() as u32 - offset;
+	}
+
+	for frame in &mut model.frames {
+		frame.vertices = kept.iter().map(|&vertex| frame.vertices[vertex]).collect();
+	}
+}
+
+/// Inverse transpose of the linear part of a transform, which keeps normals perpendicular to scaled or skewed surfaces.
+pub fn normal_matrix(transform: Matrix4<f32>) -> Matrix3<f32> {
+	let linear = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+
+	linear.invert().map(|inverse| inverse.transpose()).unwrap_or(linear)
+}
+
+/// CEM texture names are file names without an extension, so this strips the directories and extension from an image path.
+pub fn texture_name(path: &str) -> String {
+	let file = path.trim().rsplit(['/', '\\']).next().unwrap_or("");
+
+	match file.rfind('.') {
+		Some(dot) if dot > 0 => file[..dot].to_owned(),
+		_ => file.to_owned()
+	}
+}
@@ -0,0 +1,154 @@
+use cem::{v2, V2, Scene};
+use cgmath::{Point3, Matrix4, Deg, InnerSpace};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use lod::lod_name;
+
+/// Name of a material as it appears in `usemtl` and `newmtl`, which may not be empty or contain whitespace.
+/// Unnamed materials are named after their model, since all models in a scene share one material library.
+fn material_name(model_name: &str, index: usize, material: &v2::Material) -> String {
+	if material.name.trim().is_empty() {
+		format!("{}_material{}", object_name(model_name), index)
+	} else {
+		material.name.split_whitespace().collect::<Vec<_>>().join("_")
+	}
+}
+
+fn object_name(name: &str) -> String {
+	let name = name.split_whitespace().collect::<Vec<_>>().join("_");
+
+	if name.is_empty() { "Scene_Root".to_owned() } else { name }
+}
+
+/// Every model in the scene tree, parents before their children.
+fn flatten<'s>(scene: &'s Scene<V2>, models: &mut Vec<&'s Scene<V2>>) {
+	models.push(scene);
+
+	for child in &scene.children {
+		flatten(child, models);
+	}
+}
+
+/// Every model in the scene tree with the frame to export of it, which is `frame_index` or else the first frame of
+/// submodels with fewer frames. Models without any frames are left out. Warnings are tagged with `format`.
+pub fn select_frames<'s>(cem: &'s Scene<V2>, frame_index: usize, format: &str) -> Vec<(&'s Scene<V2>, &'s v2::Frame)> {
+	let mut models = Vec::new();
+	flatten(cem, &mut models);
+
+	models.into_iter().filter_map(|scene| {
+		let frames = &scene.model.frames;
+
+		if frame_index >= frames.len() && !frames.is_empty() {
+			eprintln!("warning[{}]: submodel {} only has {} frames, exporting its first frame instead", format, scene.name, frames.len());
+		}
+
+		frames.get(frame_index).or(frames.first()).map(|frame| (scene, frame))
+	}).collect()
+}
+
+/// Path of one file in an OBJ sequence, `name_0000.obj` for the first frame of `name.obj`.
+pub fn frame_path(path: &Path, frame_index: usize) -> PathBuf {
+	let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+	let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("obj");
+
+	path.with_file_name(format!("{}_{:04}.{}", stem, frame_index, extension))
+}
+
+pub fn convert(cem: &Scene<V2>, frame_index: usize, material_library: Option<&str>) -> String {
+	let mut string = String::new();
+
+	if let Some(library) = material_library {
+		writeln!(string, "mtllib {}", library).unwrap();
+	}
+
+	// OBJ indices count from the start of the file, not the start of the object.
+	let mut base_index = 0;
+
+	for (scene, frame) in select_frames(cem, frame_index, "obj") {
+		let model = &scene.model;
+
+		writeln!(string, "o {}", object_name(&scene.name)).unwrap();
+
+		let transformation = Matrix4::from_angle_x(Deg(-90.0));
+
+		for &v2::Vertex { position, normal, texture } in frame.vertices.iter() {
+
+			let normal = (transformation * normal.normalize().extend(0.0)).truncate();
+			let position = Point3::from_homogeneous(transformation * position.to_homogeneous());
+
+			writeln!(string, "v {} {} {}", position.x, position.y, position.z).unwrap();
+			writeln!(string, "vn {} {} {}", normal.x, normal.y, normal.z).unwrap();
+			writeln!(string, "vt {} {}", texture.x, 1.0 - texture.y).unwrap();
+		}
+
+		for (lod, triangle_data) in model.lod_levels.iter().enumerate() {
+			for (material_index, material) in model.materials.iter().enumerate() {
+				let &v2::Material { ref name, texture, ref triangles, vertex_offset, vertex_count: _vertex_count, ref texture_name } = material;
+
+				let triangle_slice = match triangles.get(lod) {
+					Some(&slice) => slice,
+					None => continue
+				};
+
+				writeln!(string, "# name: {}, texture: {}, texture_name: {}, lod: {}", name, texture, texture_name, lod).unwrap();
+
+				let obj_name = material_name(&scene.name, material_index, material);
+
+				writeln!(string, "g {}", lod_name(&obj_name, lod)).unwrap();
+				writeln!(string, "usemtl {}", obj_name).unwrap();
+
+				for index in 0..triangle_slice.len {
+					let index = index + triangle_slice.offset;
+					let triangle = &triangle_data[index as usize];
+
+					let indices = (
+						base_index + vertex_offset + triangle.0 + 1,
+						base_index + vertex_offset + triangle.1 + 1,
+						base_index + vertex_offset + triangle.2 + 1
+					);
+
+					writeln!(string, "f {}/{}/{} {}/{}/{} {}/{}/{}", indices.0, indices.0, indices.0, indices.1, indices.1, indices.1, indices.2, indices.2, indices.2).unwrap();
+				}
+			}
+		}
+
+		base_index += frame.vertices.len() as u32;
+	}
+
+	string
+}
+
+/// Builds the material library for an exported OBJ, with `map_Kd` pointing at `<texture_name>.<texture_extension>`.
+pub fn write_mtl(cem: &Scene<V2>, texture_extension: &str) -> String {
+	let mut string = String::new();
+	let mut written = HashSet::new();
+
+	let mut models = Vec::new();
+	flatten(cem, &mut models);
+
+	for scene in models {
+		for (material_index, material) in scene.model.materials.iter().enumerate() {
+			let name = material_name(&scene.name, material_index, material);
+
+			// Submodels often share materials, which only need to be defined once.
+			if !written.insert(name.clone()) {
+				continue;
+			}
+
+			writeln!(string, "newmtl {}", name).unwrap();
+			string.push_str("Ka 1.0 1.0 1.0\n");
+			string.push_str("Kd 1.0 1.0 1.0\n");
+			string.push_str("Ks 0.0 0.0 0.0\n");
+			string.push_str("illum 1\n");
+
+			if !material.texture_name.is_empty() {
+				writeln!(string, "map_Kd {}.{}", material.texture_name, texture_extension).unwrap();
+			}
+
+			string.push('\n');
+		}
+	}
+
+	string
+}
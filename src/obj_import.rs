@@ -0,0 +1,281 @@
+use cem::{v2, V2, collider};
+use cgmath::{Point2, Point3, Vector3, Matrix4, Deg, InnerSpace};
+use wavefront_obj::obj::{self, Object, Geometry, Shape, Primitive, VTNIndex};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use error::ConversionError;
+use lod::split_lod;
+
+/// Loads the material library referenced by `mtllib`, which is relative to the OBJ file.
+///
+/// A missing or unreadable library is not fatal, the materials simply end up without textures.
+pub fn load_mtl(obj_path: Option<&str>, library: &str) -> HashMap<String, String> {
+	let path = match obj_path.and_then(|path| Path::new(path).parent()) {
+		Some(parent) => parent.join(library),
+		None => Path::new(library).to_path_buf()
+	};
+
+	let mut buffer = String::new();
+
+	match File::open(&path).and_then(|mut file| file.read_to_string(&mut buffer)) {
+		Ok(_) => parse_mtl(&buffer),
+		Err(e) => {
+			eprintln!("warning[obj]: failed to read the material library at {} ({}), materials will not have textures", path.display(), e);
+
+			HashMap::new()
+		}
+	}
+}
+
+/// Reads the diffuse texture of every material in a .mtl file, keyed by material name.
+///
+/// Only the file stem of `map_Kd` is kept, since CEM texture names carry neither a directory nor an extension.
+pub fn parse_mtl(mtl: &str) -> HashMap<String, String> {
+	let mut textures = HashMap::new();
+	let mut current = None;
+
+	for line in mtl.lines() {
+		let mut tokens = line.split_whitespace();
+
+		match tokens.next() {
+			Some("newmtl") => current = tokens.next().map(str::to_owned),
+			Some("map_Kd") => {
+				// Texture options such as "-s 1 1 1" come before the file name.
+				let file = match tokens.last() {
+					Some(file) => file,
+					None => continue
+				};
+
+				let stem = Path::new(file).file_stem().and_then(|stem| stem.to_str()).unwrap_or(file);
+
+				if let Some(ref material) = current {
+					textures.insert(material.clone(), stem.to_owned());
+				}
+			},
+			_ => ()
+		}
+	}
+
+	textures
+}
+
+/// The file after this one in a numbered OBJ sequence, keeping the width of the number: `name_0007.obj` is followed by `name_0008.obj`.
+pub fn next_in_sequence(path: &Path) -> Option<PathBuf> {
+	let stem = path.file_stem()?.to_str()?;
+	let digits = stem.chars().rev().take_while(|c| c.is_ascii_digit()).count();
+
+	if digits == 0 {
+		return None;
+	}
+
+	let (prefix, number) = stem.split_at(stem.len() - digits);
+	let next = number.parse::<u64>().ok()? + 1;
+
+	let mut file_name = format!("{}{:0width$}", prefix, next, width = digits);
+
+	if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+		file_name.push('.');
+		file_name.push_str(extension);
+	}
+
+	Some(path.with_file_name(file_name))
+}
+
+/// Appends the first frame of `frame` to `model`, as long as both were built from the same faces.
+///
+/// Since vertices are deduplicated in the order the faces reference them, identical face lists produce identical
+/// vertex layouts, which is what makes it valid to use the vertices of one as a frame of the other.
+pub fn append_frame(model: &mut V2, frame: V2) -> Result<(), ConversionError> {
+	let index = model.frames.len();
+
+	let mismatch = |message: String| ConversionError::MismatchedFrame { index, message };
+
+	if model.materials.len() != frame.materials.len() {
+		return Err(mismatch(format!("{} materials instead of {}", frame.materials.len(), model.materials.len())));
+	}
+
+	for (base, other) in model.materials.iter().zip(frame.materials.iter()) {
+		let same_triangles = base.triangles.len() == other.triangles.len() && base.triangles.iter().zip(other.triangles.iter())
+			.all(|(a, b)| a.offset == b.offset && a.len == b.len);
+
+		if base.name != other.name || base.vertex_offset != other.vertex_offset || base.vertex_count != other.vertex_count || !same_triangles {
+			return Err(mismatch(format!("material {:?} covers different faces or vertices", other.name)));
+		}
+	}
+
+	if model.lod_levels != frame.lod_levels {
+		return Err(mismatch("the faces differ".to_string()));
+	}
+
+	model.frames.extend(frame.frames.into_iter().take(1));
+
+	Ok(())
+}
+
+/// Level of detail of a face, taken from the `_lod<n>` suffix of its group or object name.
+fn shape_lod(object: &Object, shape: &Shape) -> usize {
+	shape.groups.iter()
+		.map(|group| split_lod(group).1)
+		.chain(Some(split_lod(&object.name).1))
+		.max()
+		.unwrap_or(0)
+}
+
+/// Converts OBJ objects to a single frame model. Texture names are looked up in `textures` when given, and left empty
+/// otherwise, which is what frames beyond the first in a sequence use.
+pub fn convert(i: &[Object], textures: Option<&HashMap<String, String>>) -> V2 {
+	// Group geometry by material, keeping the materials in the order they first appear.
+	let mut material_names: Vec<Option<&str>> = Vec::new();
+	let mut groups: Vec<Vec<(usize, &Geometry)>> = Vec::new();
+
+	for (idx, object) in i.iter().enumerate() {
+		for geometry in &object.geometry {
+			let name = geometry.material_name.as_ref().map(|s| s as &str);
+
+			let group = match material_names.iter().position(|&existing| existing == name) {
+				Some(group) => group,
+				None => {
+					material_names.push(name);
+					groups.push(Vec::new());
+
+					groups.len() - 1
+				}
+			};
+
+			groups[group].push((idx, geometry));
+		}
+	}
+
+	let mut vertices = Vec::new();
+	// Triangles of each material, split by level of detail
+	let mut material_triangles: Vec<Vec<Vec<(u32, u32, u32)>>> = Vec::with_capacity(groups.len());
+	let mut materials = Vec::with_capacity(groups.len());
+
+	let transformation = Matrix4::from_angle_x(Deg(90.0));
+
+	for (name, group) in material_names.iter().zip(groups.iter()) {
+		let vertex_offset = vertices.len();
+		let mut lod_triangles: Vec<Vec<(u32, u32, u32)>> = Vec::new();
+
+		{
+			// Each material has its own vertex range shared by all levels of detail, so indices are relative to the start of that range.
+			let mut vertex_associations = HashMap::new();
+
+			let mut resolve_index = |i: &Object, idx: usize, v: VTNIndex| {
+				*vertex_associations.entry((idx, v)).or_insert_with(|| {
+					let index = vertices.len() - vertex_offset;
+
+					let position = i.vertices[v.0];
+					let texture = v.1.map(|index| i.tex_vertices[index]).unwrap_or(obj::TVertex { u: 0.0, v: 0.0, w: 0.0 });
+					let normal = v.2.map(|index| i.normals[index]).unwrap_or(obj::Vertex { x: 1.0, y: 0.0, z: 0.0 });
+
+					let normal = Vector3 { x: normal.x as f32, y: normal.y as f32, z: normal.z as f32 };
+					let position = Point3 { x: position.x as f32, y: position.y as f32, z: position.z as f32 };
+
+					let normal = (transformation * normal.normalize().extend(0.0)).truncate();
+					let position = Point3::from_homogeneous(transformation * position.to_homogeneous());
+
+					vertices.push(v2::Vertex {
+						position,
+						normal,
+						texture: Point2 { x: texture.u as f32, y: 1.0 - texture.v as f32 },
+					});
+
+					index
+				})
+			};
+
+			for &(idx, geometry) in group {
+				let i = &i[idx];
+
+				for shape in &geometry.shapes {
+					// Skip lines and points, not supported.
+					if let Primitive::Triangle(v0, v1, v2) = shape.primitive {
+						let lod = shape_lod(i, shape);
+
+						while lod_triangles.len() <= lod {
+							lod_triangles.push(Vec::new());
+						}
+
+						lod_triangles[lod].push((
+							resolve_index(i, idx, v0) as u32,
+							resolve_index(i, idx, v1) as u32,
+							resolve_index(i, idx, v2) as u32
+						));
+					}
+				}
+			}
+		}
+
+		let name = name.unwrap_or("");
+
+		let texture_name = match textures {
+			Some(textures) => textures.get(name).cloned().unwrap_or_else(|| {
+				if !name.is_empty() {
+					eprintln!("warning[obj]: material {} has no diffuse texture (map_Kd) in the material library", name);
+				}
+
+				String::new()
+			}),
+			None => String::new()
+		};
+
+		materials.push(v2::Material {
+			name: name.to_string(),
+			texture: 0,
+			triangles: vec![],
+			vertex_offset: vertex_offset as u32,
+			vertex_count: (vertices.len() - vertex_offset) as u32,
+			texture_name
+		});
+
+		material_triangles.push(lod_triangles);
+	}
+
+	// Lay out each level of detail material by material, so that every material selects a contiguous range.
+	let lod_count = material_triangles.iter().map(Vec::len).max().unwrap_or(0).max(1);
+	let mut lod_levels = vec![Vec::new(); lod_count];
+
+	for (material, lod_triangles) in materials.iter_mut().zip(material_triangles) {
+		for (lod, triangles) in lod_levels.iter_mut().enumerate() {
+			let offset = triangles.len();
+
+			if let Some(selected) = lod_triangles.get(lod) {
+				triangles.extend_from_slice(selected);
+			}
+
+			material.triangles.push(v2::TriangleSelection {
+				offset: offset as u32,
+				len: (triangles.len() - offset) as u32
+			});
+		}
+	}
+
+	for (lod, triangles) in lod_levels.iter().enumerate().skip(1) {
+		if triangles.is_empty() {
+			eprintln!("warning[obj]: level of detail {} has no faces", lod);
+		}
+	}
+
+	// Create the model
+
+	let mut center_builder = collider::CenterBuilder::begin();
+
+	for vertex in &vertices {
+		center_builder.update(vertex.position);
+	}
+
+	let center = center_builder.build();
+
+	V2 {
+		center,
+		materials,
+		lod_levels,
+		tag_points: vec![],
+		frames: vec![
+			v2::Frame::from_vertices(vertices, vec![], center)
+		]
+	}
+}
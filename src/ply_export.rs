@@ -0,0 +1,119 @@
+//! Stanford PLY export of a single frame and level of detail, for inspecting the geometry in generic viewers.
+//!
+//! Unlike the OBJ and COLLADA exports, positions stay in the Z-up coordinates of CEM, which is also what slicers expect
+//! from the STL export built on top of this.
+
+use cem::{v2, V2, Scene};
+use cgmath::InnerSpace;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fmt::Write;
+use obj_export::select_frames;
+
+/// The triangles of one frame and level of detail of every model in a scene, in one vertex buffer.
+pub struct Mesh {
+	pub vertices: Vec<v2::Vertex>,
+	pub triangles: Vec<(u32, u32, u32)>
+}
+
+/// Collects a frame and level of detail of every model in the scene, keeping only the vertices that level of detail
+/// uses. Frames are picked like in the OBJ export, and submodels without the level of detail fall back to their lowest
+/// one. Warnings are tagged with `format`.
+pub fn mesh(cem: &Scene<V2>, frame_index: usize, lod: usize, format: &str) -> Mesh {
+	let mut vertices = Vec::new();
+	let mut triangles = Vec::new();
+
+	for (scene, frame) in select_frames(cem, frame_index, format) {
+		let model = &scene.model;
+
+		let triangle_data = match model.lod_levels.get(lod).or(model.lod_levels.last()) {
+			Some(triangle_data) => triangle_data,
+			None => continue
+		};
+
+		let model_lod = lod.min(model.lod_levels.len() - 1);
+
+		if model_lod != lod {
+			eprintln!("warning[{}]: submodel {} only has {} levels of detail, exporting its lowest instead", format, scene.name, model.lod_levels.len());
+		}
+
+		// Index of each vertex of the frame in the mesh, once a triangle uses it.
+		let mut remap = vec![None; frame.vertices.len()];
+
+		let mut index = |vertex: u32| *remap[vertex as usize].get_or_insert_with(|| {
+			let vertex = &frame.vertices[vertex as usize];
+
+			vertices.push(v2::Vertex {
+				position: vertex.position,
+				normal: vertex.normal.normalize(),
+				texture: vertex.texture
+			});
+
+			(vertices.len() - 1) as u32
+		});
+
+		for material in &model.materials {
+			let selection = match material.triangles.get(model_lod) {
+				Some(&selection) => selection,
+				None => continue
+			};
+
+			for triangle in &triangle_data[selection.offset as usize..(selection.offset + selection.len) as usize] {
+				let offset = material.vertex_offset;
+
+				triangles.push((index(offset + triangle.0), index(offset + triangle.1), index(offset + triangle.2)));
+			}
+		}
+	}
+
+	Mesh { vertices, triangles }
+}
+
+/// Converts a frame and level of detail of a scene to PLY, either ASCII or binary little endian. Texture coordinates are
+/// flipped to start at the bottom left, like in the OBJ export.
+pub fn convert(cem: &Scene<V2>, frame_index: usize, lod: usize, binary: bool) -> Vec<u8> {
+	let Mesh { vertices, triangles } = mesh(cem, frame_index, lod, "ply");
+
+	let mut header = String::new();
+
+	header.push_str("ply\n");
+	header.push_str(if binary { "format binary_little_endian 1.0\n" } else { "format ascii 1.0\n" });
+	writeln!(header, "comment cemconv export of frame {}, level of detail {}", frame_index, lod).unwrap();
+	writeln!(header, "element vertex {}", vertices.len()).unwrap();
+	header.push_str("property float x\nproperty float y\nproperty float z\n");
+	header.push_str("property float nx\nproperty float ny\nproperty float nz\n");
+	header.push_str("property float s\nproperty float t\n");
+	writeln!(header, "element face {}", triangles.len()).unwrap();
+	header.push_str("property list uchar uint vertex_indices\n");
+	header.push_str("end_header\n");
+
+	let mut data = header.into_bytes();
+
+	if binary {
+		for vertex in &vertices {
+			for &value in &[vertex.position.x, vertex.position.y, vertex.position.z, vertex.normal.x, vertex.normal.y, vertex.normal.z, vertex.texture.x, 1.0 - vertex.texture.y] {
+				data.write_f32::<LittleEndian>(value).unwrap();
+			}
+		}
+
+		for &(a, b, c) in &triangles {
+			data.push(3);
+			data.write_u32::<LittleEndian>(a).unwrap();
+			data.write_u32::<LittleEndian>(b).unwrap();
+			data.write_u32::<LittleEndian>(c).unwrap();
+		}
+	} else {
+		let mut string = String::new();
+
+		for &v2::Vertex { position, normal, texture } in &vertices {
+			writeln!(string, "{} {} {} {} {} {} {} {}", position.x, position.y, position.z, normal.x, normal.y, normal.z, texture.x, 1.0 - texture.y).unwrap();
+		}
+
+		for &(a, b, c) in &triangles {
+			writeln!(string, "3 {} {} {}", a, b, c).unwrap();
+		}
+
+		data.extend_from_slice(string.as_bytes());
+	}
+
+	data
+}
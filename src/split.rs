@@ -0,0 +1,213 @@
+//! It appears that there is some limit on the vertex count. This needs to be investigated further, but it appears that
+//! adding more than 2442 instantly crashes the game on model load. Models over the limit can be split into submodels
+//! that each stay under it.
+
+use cem::{v2, V2, Scene, collider};
+use std::collections::{HashMap, HashSet};
+
+pub const VERTEX_LIMIT: usize = 2442;
+
+fn vertex_count(model: &V2) -> usize {
+	model.frames.first().map(|frame| frame.vertices.len()).unwrap_or(0)
+}
+
+/// Warns about every model in the scene that is over the vertex limit.
+pub fn warn_oversized(scene: &Scene<V2>) {
+	let vertices = vertex_count(&scene.model);
+
+	if vertices > VERTEX_LIMIT {
+		eprintln!("warning[cem]: Vertex count exceeds {}, this will most likely crash the game. You have been warned.", VERTEX_LIMIT);
+		eprintln!("warning[cem]: {} vertices, {} triangles in {} (use --split to split it into submodels)", vertices, scene.model.lod_levels.first().map(Vec::len).unwrap_or(0), scene.name);
+	}
+
+	for child in &scene.children {
+		warn_oversized(child);
+	}
+}
+
+/// Splits every model in the scene that is over the vertex limit, adding the extra parts as children named `<name>_part<n>`.
+pub fn split_scene(scene: &mut Scene<V2>) {
+	for child in &mut scene.children {
+		split_scene(child);
+	}
+
+	if vertex_count(&scene.model) <= VERTEX_LIMIT {
+		return;
+	}
+
+	let mut parts = split_model(&scene.model);
+
+	eprintln!("info[cem]: split {} into {} parts to stay under the vertex limit of {}", scene.name, parts.len(), VERTEX_LIMIT);
+
+	let rest = parts.split_off(1);
+	scene.model = parts.pop().unwrap();
+
+	for (index, part) in rest.into_iter().enumerate() {
+		scene.children.push(Scene {
+			name: format!("{}_part{}", scene.name, index + 1),
+			model: part,
+			children: Vec::new()
+		});
+	}
+}
+
+struct Chunk {
+	/// Vertices used by this chunk, as indices into the vertex buffer of the original model.
+	vertices: HashSet<u32>,
+	/// For each level of detail, the triangles of this chunk as (material index, original vertex indices).
+	triangles: Vec<Vec<(usize, [u32; 3])>>
+}
+
+impl Chunk {
+	fn new(lod_count: usize) -> Self {
+		Chunk { vertices: HashSet::new(), triangles: vec![Vec::new(); lod_count] }
+	}
+
+	fn missing(&self, triangle: &[u32; 3]) -> usize {
+		let mut missing = triangle.iter().filter(|vertex| !self.vertices.contains(*vertex)).collect::<Vec<_>>();
+		missing.sort();
+		missing.dedup();
+
+		missing.len()
+	}
+}
+
+fn split_model(model: &V2) -> Vec<V2> {
+	let lod_count = model.lod_levels.len();
+	let mut chunks = vec![Chunk::new(lod_count)];
+
+	// Greedily put each triangle in the chunk that already has most of its vertices and room for the rest.
+	for (lod, triangle_data) in model.lod_levels.iter().enumerate() {
+		for (material_index, material) in model.materials.iter().enumerate() {
+			let selection = match material.triangles.get(lod) {
+				Some(&selection) => selection,
+				None => continue
+			};
+
+			for &(a, b, c) in &triangle_data[selection.offset as usize..(selection.offset + selection.len) as usize] {
+				let offset = material.vertex_offset;
+				let triangle = [offset + a, offset + b, offset + c];
+
+				let mut best: Option<(usize, usize)> = None;
+
+				for (index, chunk) in chunks.iter().enumerate() {
+					let missing = chunk.missing(&triangle);
+
+					if chunk.vertices.len() + missing > VERTEX_LIMIT {
+						continue;
+					}
+
+					// Prefer the later chunks on ties, since the triangles of a mesh are usually somewhat ordered.
+					if best.map(|(_, best_missing)| missing <= best_missing).unwrap_or(true) {
+						best = Some((index, missing));
+					}
+				}
+
+				let index = match best {
+					Some((index, missing)) if missing < 3 || index == chunks.len() - 1 => index,
+					_ => {
+						chunks.push(Chunk::new(lod_count));
+						chunks.len() - 1
+					}
+				};
+
+				let chunk = &mut chunks[index];
+
+				chunk.vertices.extend(triangle.iter().cloned());
+				chunk.triangles[lod].push((material_index, triangle));
+			}
+		}
+	}
+
+	chunks.iter().enumerate().map(|(index, chunk)| build_chunk(model, chunk, index == 0)).collect()
+}
+
+/// Builds a standalone model out of a chunk. Tag points stay with the first chunk.
+fn build_chunk(model: &V2, chunk: &Chunk, keep_tag_points: bool) -> V2 {
+	let mut used = chunk.vertices.iter().cloned().collect::<Vec<u32>>();
+	used.sort();
+
+	// Original vertex indices of the new vertex buffer, material by material.
+	let mut order = Vec::with_capacity(used.len());
+	let mut materials = Vec::new();
+	// Maps each material of the original model to its index and vertex offset in the new model.
+	let mut material_map = vec![None; model.materials.len()];
+
+	for (material_index, material) in model.materials.iter().enumerate() {
+		let start = material.vertex_offset;
+		let end = material.vertex_offset + material.vertex_count;
+
+		let vertex_offset = order.len() as u32;
+		order.extend(used.iter().cloned().filter(|&vertex| vertex >= start && vertex < end));
+
+		if order.len() as u32 == vertex_offset {
+			continue;
+		}
+
+		material_map[material_index] = Some((materials.len(), vertex_offset));
+
+		materials.push(v2::Material {
+			name: material.name.clone(),
+			texture: material.texture,
+			triangles: Vec::with_capacity(chunk.triangles.len()),
+			vertex_offset,
+			vertex_count: order.len() as u32 - vertex_offset,
+			texture_name: material.texture_name.clone()
+		});
+	}
+
+	let new_index = order.iter().enumerate().map(|(new, &old)| (old, new as u32)).collect::<HashMap<u32, u32>>();
+
+	let mut lod_levels = Vec::with_capacity(chunk.triangles.len());
+
+	for triangles in &chunk.triangles {
+		let mut level = Vec::with_capacity(triangles.len());
+
+		for (material_index, &(new_material, vertex_offset)) in material_map.iter().enumerate().filter_map(|(index, mapped)| mapped.as_ref().map(|mapped| (index, mapped))) {
+			let start = level.len();
+
+			for &(_, triangle) in triangles.iter().filter(|&&(material, _)| material == material_index) {
+				level.push((
+					new_index[&triangle[0]] - vertex_offset,
+					new_index[&triangle[1]] - vertex_offset,
+					new_index[&triangle[2]] - vertex_offset
+				));
+			}
+
+			materials[new_material].triangles.push(v2::TriangleSelection {
+				offset: start as u32,
+				len: (level.len() - start) as u32
+			});
+		}
+
+		lod_levels.push(level);
+	}
+
+	let mut center = None;
+
+	let frames = model.frames.iter().map(|frame| {
+		let vertices = order.iter().map(|&vertex| frame.vertices[vertex as usize]).collect::<Vec<_>>();
+
+		let frame_center = *center.get_or_insert_with(|| {
+			let mut center_builder = collider::CenterBuilder::begin();
+
+			for vertex in &vertices {
+				center_builder.update(vertex.position);
+			}
+
+			center_builder.build()
+		});
+
+		let tag_points = if keep_tag_points { frame.tag_points.clone() } else { vec![] };
+
+		v2::Frame::from_vertices(vertices, tag_points, frame_center)
+	}).collect::<Vec<_>>();
+
+	V2 {
+		center: center.unwrap_or(model.center),
+		materials,
+		lod_levels,
+		tag_points: if keep_tag_points { model.tag_points.clone() } else { vec![] },
+		frames
+	}
+}
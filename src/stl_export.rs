@@ -0,0 +1,43 @@
+//! Binary STL export of a single frame and level of detail, mostly for 3D printing.
+
+use cem::{V2, Scene};
+use cgmath::{InnerSpace, Vector3};
+use byteorder::{LittleEndian, WriteBytesExt};
+use ply_export::{self, Mesh};
+
+/// Converts a frame and level of detail of a scene to binary STL. Normals are recomputed per triangle, as STL has no
+/// vertex normals, and texture coordinates are dropped.
+pub fn convert(cem: &Scene<V2>, frame_index: usize, lod: usize) -> Vec<u8> {
+	let Mesh { vertices, triangles } = ply_export::mesh(cem, frame_index, lod, "stl");
+
+	let mut data = Vec::with_capacity(84 + triangles.len() * 50);
+
+	let mut header = format!("cemconv export of {}, frame {}, level of detail {}", cem.name, frame_index, lod).into_bytes();
+	header.resize(80, 0);
+
+	data.extend_from_slice(&header);
+	data.write_u32::<LittleEndian>(triangles.len() as u32).unwrap();
+
+	for &(a, b, c) in &triangles {
+		let positions = [vertices[a as usize].position, vertices[b as usize].position, vertices[c as usize].position];
+
+		let normal = (positions[1] - positions[0]).cross(positions[2] - positions[0]);
+
+		// Degenerate triangles get a zero normal, which slicers recompute from the winding anyway.
+		let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::new(0.0, 0.0, 0.0) };
+
+		for &value in &[normal.x, normal.y, normal.z] {
+			data.write_f32::<LittleEndian>(value).unwrap();
+		}
+
+		for position in &positions {
+			for &value in &[position.x, position.y, position.z] {
+				data.write_f32::<LittleEndian>(value).unwrap();
+			}
+		}
+
+		data.write_u16::<LittleEndian>(0).unwrap();
+	}
+
+	data
+}
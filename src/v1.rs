@@ -0,0 +1,124 @@
+//! Upgrades CEM 1.3 models, as read by `cem::v1`, to CEMv2 for the exporters.
+//!
+//! A 1.3 model has a single level of detail, but has tag points (since 1.1) and submodels (since 1.2), which are kept.
+//! Parts of the format are still not understood, so the upgrade assumes that:
+//!
+//! * each triangle corner refers to an entry of the vertex table, whose first value is the position in each frame
+//! * the indices of a material are triangle indices, triangles of no material get an unnamed one
+//!
+//! The quantized normals are not decoded, normals are recomputed from the triangles of each frame instead.
+
+use cem::{v1, v2, V2, Scene, ModelHeader, Encode};
+use cgmath::{InnerSpace, Point2, Vector3};
+use mesh_builder::{MaterialBuilder, layout, texture_name};
+use std::io::{self, Read};
+
+pub use cem::v1::EXPECTED_MODEL_HEADER as HEADER;
+
+/// Reads a 1.3 model after its header and upgrades it, along with its submodels.
+pub fn read_without_header<R>(r: &mut R) -> io::Result<Scene<V2>> where R: Read {
+	let (model, node) = v1::V1::read(r)?;
+	let (name, additional_models) = (node.name.into_owned(), node.additional_models);
+
+	let mut scene = Scene::single(name, upgrade(model)?);
+
+	for _ in 0..additional_models {
+		let header = ModelHeader::read(r)?;
+
+		if header != HEADER {
+			return Err(invalid_data(format!("wrong submodel header: expected {:?}, got {:?}", HEADER, header)));
+		}
+
+		scene.children.push(read_without_header(r)?);
+	}
+
+	Ok(scene)
+}
+
+/// Converts a single model, without its submodels.
+fn upgrade(model: v1::V1) -> io::Result<V2> {
+	let v1::V1 { quantities, center, triangles, materials, vertices, tag_points, frames, .. } = model;
+	let point_count = quantities.vertex_points as usize;
+
+	// Material of each triangle, the first material listing a triangle wins.
+	let mut owners = vec![None; triangles.len()];
+
+	for (index, material) in materials.iter().enumerate() {
+		for &triangle in &material.indices {
+			match owners.get_mut(triangle as usize) {
+				Some(owner) => { owner.get_or_insert(index); },
+				None => return Err(invalid_data(format!("material {} refers to triangle {}, but there are only {}", index, triangle, triangles.len())))
+			}
+		}
+	}
+
+	let mut builders = Vec::new();
+
+	for ((a, b, c), owner) in triangles.iter().zip(&owners) {
+		let material = match *owner {
+			Some(index) => {
+				let texture = materials[index].texture.as_ref().map(|(path, _)| texture_name(path)).unwrap_or_default();
+
+				(format!("material{}", index), texture)
+			},
+			None => (String::new(), String::new())
+		};
+
+		let builder = MaterialBuilder::select(&mut builders, material, 0);
+		let mut corner = |vertex: &v1::Vertex| -> io::Result<u32> {
+			match vertices.get(vertex.unknown0 as usize) {
+				Some(&(point, _)) if (point as usize) < point_count => (),
+				Some(&(point, _)) => return Err(invalid_data(format!("vertex {} refers to point {}, but there are only {}", vertex.unknown0, point, point_count))),
+				None => return Err(invalid_data(format!("triangle refers to vertex {}, but there are only {}", vertex.unknown0, vertices.len())))
+			}
+
+			Ok(builder.dedup((vertex.unknown0, vertex.uv.0.to_bits(), vertex.uv.1.to_bits())))
+		};
+
+		let triangle = (corner(a)?, corner(b)?, corner(c)?);
+
+		builder.push(0, triangle, false);
+	}
+
+	let (associations, lod_levels, v2_materials) = layout(builders, 1);
+
+	let point = |vertex: u32| vertices[vertex as usize].0 as usize;
+
+	let frames = frames.into_iter().map(|frame| {
+		// Area weighted normals of the triangles around each point, smooth across texture seams.
+		let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); point_count];
+
+		for (a, b, c) in &triangles {
+			let points = [point(a.unknown0), point(b.unknown0), point(c.unknown0)];
+			let normal = (frame.points[points[1]] - frame.points[points[0]]).cross(frame.points[points[2]] - frame.points[points[0]]);
+
+			for &point in &points {
+				normals[point] += normal;
+			}
+		}
+
+		let vertices = associations.iter().map(|&(vertex, u, v)| {
+			let normal = normals[point(vertex)];
+
+			v2::Vertex {
+				position: frame.points[point(vertex)],
+				normal: if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::unit_z() },
+				texture: Point2::new(f32::from_bits(u), f32::from_bits(v))
+			}
+		}).collect();
+
+		v2::Frame::from_vertices(vertices, frame.tag_points, center)
+	}).collect();
+
+	Ok(V2 {
+		center,
+		materials: v2_materials,
+		lod_levels,
+		tag_points,
+		frames
+	})
+}
+
+fn invalid_data(message: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}